@@ -0,0 +1,375 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use trust_dns_client::{
+    op::{Message, Query, ResponseCode},
+    rr::{DNSClass, LowerName, RData, Record, RecordType},
+};
+
+/// Upstream answers keyed by (name, type, class) and the client subnet
+/// sent along.
+///
+/// Entries live for the smallest ttl of their records, clamped to
+/// `[min_ttl, max_ttl]`. Once expired an entry is kept for `stale` more,
+/// to be served when the upstream fails. The least recently used entry is
+/// dropped when the cache is full.
+pub struct DnsCache {
+    entries: HashMap<Key, Entry>,
+    lru: BTreeMap<u64, Key>,
+    tick: u64,
+    size: usize,
+    min_ttl: u32,
+    max_ttl: u32,
+    stale: Duration,
+    prefetch: bool,
+}
+
+type Key = (LowerName, RecordType, DNSClass, Option<IpNet>);
+
+struct Entry {
+    message: Message,
+    ttl: u32,
+    inserted: Instant,
+    hits: u64,
+    tick: u64,
+    prefetching: bool,
+}
+
+// ttl of the records of a stale answer, RFC 8767
+const STALE_TTL: u32 = 30;
+
+// hits before an entry is worth a prefetch
+const PREFETCH_HITS: u64 = 3;
+
+impl DnsCache {
+    pub fn new(size: usize, min_ttl: u32, max_ttl: u32, stale: u32, prefetch: bool) -> Self {
+        DnsCache {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            size,
+            min_ttl,
+            max_ttl,
+            stale: Duration::from_secs(stale as u64),
+            prefetch,
+        }
+    }
+
+    /// Returns the fresh answer of `query` with the ttls counted down. The
+    /// flag asks the caller to refresh the entry in background, set once
+    /// for a popular entry in the last tenth of its ttl.
+    pub fn get(
+        &mut self,
+        query: &Query,
+        subnet: Option<IpNet>,
+        now: Instant,
+    ) -> Option<(Message, bool)> {
+        let key = key(query, subnet);
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(&key)?;
+        let elapsed = now.saturating_duration_since(entry.inserted).as_secs() as u32;
+        if elapsed >= entry.ttl {
+            return None;
+        }
+
+        self.lru.remove(&entry.tick);
+        self.lru.insert(tick, key);
+        entry.tick = tick;
+        entry.hits += 1;
+
+        let remaining = entry.ttl - elapsed;
+        let prefetch = self.prefetch
+            && !entry.prefetching
+            && entry.hits >= PREFETCH_HITS
+            && remaining * 10 <= entry.ttl;
+        if prefetch {
+            entry.prefetching = true;
+        }
+
+        let (min_ttl, max_ttl) = (self.min_ttl, self.max_ttl);
+        let message = with_ttl(&entry.message, |ttl| {
+            ttl.max(min_ttl).min(max_ttl).saturating_sub(elapsed)
+        });
+        Some((message, prefetch))
+    }
+
+    /// `message` with the ttls clamped as a cached answer would be.
+    pub fn clamp(&self, message: &Message) -> Message {
+        let (min_ttl, max_ttl) = (self.min_ttl, self.max_ttl);
+        with_ttl(message, |ttl| ttl.max(min_ttl).min(max_ttl))
+    }
+
+    /// Returns the expired answer of `query` if still within the stale
+    /// window.
+    pub fn stale(&mut self, query: &Query, subnet: Option<IpNet>, now: Instant) -> Option<Message> {
+        let entry = self.entries.get(&key(query, subnet))?;
+        let expires = entry.inserted + Duration::from_secs(entry.ttl as u64);
+        if now < expires || now >= expires + self.stale {
+            return None;
+        }
+        Some(with_ttl(&entry.message, |_| STALE_TTL))
+    }
+
+    /// Caches a NOERROR or NXDOMAIN answer, negative ones for the ttl of
+    /// the SOA record. Answers whose ttl clamps to 0 are not cached.
+    pub fn insert(
+        &mut self,
+        query: &Query,
+        subnet: Option<IpNet>,
+        message: &Message,
+        now: Instant,
+    ) {
+        match message.response_code() {
+            ResponseCode::NoError | ResponseCode::NXDomain if !message.truncated() => {}
+            _ => return,
+        }
+        let ttl = match message_ttl(message) {
+            Some(v) => v.max(self.min_ttl).min(self.max_ttl),
+            None => self.min_ttl,
+        };
+        if ttl == 0 || self.size == 0 {
+            return;
+        }
+
+        let key = key(query, subnet);
+        if let Some(old) = self.entries.remove(&key) {
+            self.lru.remove(&old.tick);
+        }
+        while self.entries.len() >= self.size {
+            let tick = *self.lru.keys().next().expect("cache lru is empty");
+            let key = self.lru.remove(&tick).unwrap();
+            self.entries.remove(&key);
+        }
+
+        let tick = self.next_tick();
+        self.lru.insert(tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                message: message.clone(),
+                ttl,
+                inserted: now,
+                hits: 0,
+                tick,
+                prefetching: false,
+            },
+        );
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+fn key(query: &Query, subnet: Option<IpNet>) -> Key {
+    (
+        LowerName::new(query.name()),
+        query.query_type(),
+        query.query_class(),
+        subnet,
+    )
+}
+
+/// The smallest ttl of the answers, for an answer without records the
+/// negative ttl of the SOA in the authority section.
+fn message_ttl(message: &Message) -> Option<u32> {
+    if !message.answers().is_empty() {
+        return message.answers().iter().map(|v| v.ttl()).min();
+    }
+    message
+        .name_servers()
+        .iter()
+        .filter_map(|v| match v.rdata() {
+            RData::SOA(soa) => Some(v.ttl().min(soa.minimum())),
+            _ => None,
+        })
+        .min()
+}
+
+fn with_ttl<F: Fn(u32) -> u32>(message: &Message, f: F) -> Message {
+    let update = |records: Vec<Record>| -> Vec<Record> {
+        records
+            .into_iter()
+            .map(|mut v| {
+                let ttl = f(v.ttl());
+                v.set_ttl(ttl);
+                v
+            })
+            .collect()
+    };
+    let mut message = message.clone();
+    let answers = update(message.take_answers());
+    let name_servers = update(message.take_name_servers());
+    let additionals = update(message.take_additionals());
+    message.insert_answers(answers);
+    message.insert_name_servers(name_servers);
+    message.insert_additionals(additionals);
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, str::FromStr};
+
+    use trust_dns_client::rr::{rdata::SOA, Name};
+
+    use super::*;
+
+    fn query(name: &str, t: RecordType) -> Query {
+        Query::query(Name::from_str(name).unwrap(), t)
+    }
+
+    fn answer(query: &Query, ttls: &[u32]) -> Message {
+        let mut message = Message::new();
+        message.add_query(query.clone());
+        for (i, ttl) in ttls.iter().enumerate() {
+            let rdata = RData::A(Ipv4Addr::new(1, 2, 3, i as u8));
+            message.add_answer(Record::from_rdata(query.name().clone(), *ttl, rdata));
+        }
+        message
+    }
+
+    fn ttls(message: &Message) -> Vec<u32> {
+        message.answers().iter().map(|v| v.ttl()).collect()
+    }
+
+    #[test]
+    fn test_get() {
+        let mut cache = DnsCache::new(16, 0, 3600, 0, false);
+        let now = Instant::now();
+        let q = query("www.example.com.", RecordType::A);
+        assert!(cache.get(&q, None, now).is_none());
+
+        cache.insert(&q, None, &answer(&q, &[300, 60]), now);
+        let (res, prefetch) = cache.get(&q, None, now).unwrap();
+        assert_eq!(ttls(&res), vec![300, 60]);
+        assert!(!prefetch);
+
+        // case insensitive, but type and class are part of the key
+        let later = now + Duration::from_secs(20);
+        let upper = query("WWW.Example.com.", RecordType::A);
+        assert_eq!(
+            ttls(&cache.get(&upper, None, later).unwrap().0),
+            vec![280, 40]
+        );
+        assert!(cache
+            .get(&query("www.example.com.", RecordType::AAAA), None, now)
+            .is_none());
+        let mut chaos = q.clone();
+        chaos.set_query_class(DNSClass::CH);
+        assert!(cache.get(&chaos, None, now).is_none());
+        let subnet = "203.0.113.0/24".parse().ok();
+        assert!(cache.get(&q, subnet, now).is_none());
+
+        assert!(cache.get(&q, None, now + Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn test_clamp() {
+        let mut cache = DnsCache::new(16, 30, 600, 0, false);
+        let now = Instant::now();
+        let q = query("www.example.com.", RecordType::A);
+        cache.insert(&q, None, &answer(&q, &[5, 86400]), now);
+        let (res, _) = cache.get(&q, None, now + Duration::from_secs(10)).unwrap();
+        assert_eq!(ttls(&res), vec![20, 590]);
+        assert!(cache.get(&q, None, now + Duration::from_secs(30)).is_none());
+        assert_eq!(ttls(&cache.clamp(&answer(&q, &[5, 86400]))), vec![30, 600]);
+
+        let mut cache = DnsCache::new(16, 0, 600, 0, false);
+        cache.insert(&q, None, &answer(&q, &[0]), now);
+        assert!(cache.get(&q, None, now).is_none());
+    }
+
+    #[test]
+    fn test_negative() {
+        let mut cache = DnsCache::new(16, 0, 3600, 0, false);
+        let now = Instant::now();
+        let q = query("nx.example.com.", RecordType::A);
+        let soa = SOA::new(
+            Name::from_str("ns.example.com.").unwrap(),
+            Name::from_str("admin.example.com.").unwrap(),
+            1,
+            3600,
+            600,
+            86400,
+            60,
+        );
+        let mut message = answer(&q, &[]);
+        message.set_response_code(ResponseCode::NXDomain);
+        message.add_name_server(Record::from_rdata(
+            Name::from_str("example.com.").unwrap(),
+            300,
+            RData::SOA(soa),
+        ));
+        cache.insert(&q, None, &message, now);
+        let (res, _) = cache.get(&q, None, now + Duration::from_secs(59)).unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        assert_eq!(res.name_servers()[0].ttl(), 241);
+        assert!(cache.get(&q, None, now + Duration::from_secs(60)).is_none());
+
+        let mut message = answer(&q, &[300]);
+        message.set_response_code(ResponseCode::ServFail);
+        let q = query("servfail.example.com.", RecordType::A);
+        cache.insert(&q, None, &message, now);
+        assert!(cache.get(&q, None, now).is_none());
+    }
+
+    #[test]
+    fn test_stale() {
+        let mut cache = DnsCache::new(16, 0, 3600, 60, false);
+        let now = Instant::now();
+        let q = query("www.example.com.", RecordType::A);
+        cache.insert(&q, None, &answer(&q, &[10]), now);
+        assert!(cache.stale(&q, None, now).is_none());
+
+        let later = now + Duration::from_secs(30);
+        assert!(cache.get(&q, None, later).is_none());
+        assert_eq!(
+            ttls(&cache.stale(&q, None, later).unwrap()),
+            vec![STALE_TTL]
+        );
+        assert!(cache
+            .stale(&q, None, now + Duration::from_secs(70))
+            .is_none());
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let mut cache = DnsCache::new(16, 0, 3600, 0, true);
+        let now = Instant::now();
+        let q = query("www.example.com.", RecordType::A);
+        cache.insert(&q, None, &answer(&q, &[100]), now);
+        for _ in 0..PREFETCH_HITS {
+            assert!(!cache.get(&q, None, now).unwrap().1);
+        }
+        let later = now + Duration::from_secs(91);
+        assert!(cache.get(&q, None, later).unwrap().1);
+        assert!(!cache.get(&q, None, later).unwrap().1);
+
+        // a refreshed entry starts over
+        cache.insert(&q, None, &answer(&q, &[100]), later);
+        assert!(!cache.get(&q, None, later).unwrap().1);
+    }
+
+    #[test]
+    fn test_evict() {
+        let mut cache = DnsCache::new(2, 0, 3600, 0, false);
+        let now = Instant::now();
+        let a = query("a.example.com.", RecordType::A);
+        let b = query("b.example.com.", RecordType::A);
+        let c = query("c.example.com.", RecordType::A);
+        cache.insert(&a, None, &answer(&a, &[60]), now);
+        cache.insert(&b, None, &answer(&b, &[60]), now);
+        assert!(cache.get(&a, None, now).is_some());
+        cache.insert(&c, None, &answer(&c, &[60]), now);
+        assert!(cache.get(&a, None, now).is_some());
+        assert!(cache.get(&b, None, now).is_none());
+        assert!(cache.get(&c, None, now).is_some());
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.lru.len(), 2);
+    }
+}
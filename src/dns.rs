@@ -1,86 +1,341 @@
-use std::{borrow::BorrowMut, net::IpAddr, pin::Pin, sync::Arc};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use futures::Future;
-use tokio::{net::UdpSocket, runtime::Runtime};
+use futures::{
+    future::{try_join, try_join_all},
+    Future, StreamExt,
+};
+use ipnet::IpNet;
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    runtime::Runtime,
+};
 use trust_dns_client::{
-    op::{Header, OpCode},
-    rr::{LowerName, Record, RecordType},
+    op::{Header, Message, OpCode, Query, ResponseCode},
+    rr::{LowerName, Name, RData, Record, RecordType},
 };
-use trust_dns_proto::op::header::MessageType;
-use trust_dns_resolver::{
-    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
-    name_server::{GenericConnection, GenericConnectionProvider, TokioRuntime},
-    AsyncResolver, TokioAsyncResolver,
+use trust_dns_proto::{
+    error::{ProtoError, ProtoResult},
+    op::header::MessageType,
+    serialize::binary::{BinDecodable, BinDecoder, BinEncodable, BinEncoder},
+    udp::UdpStream,
+    xfer::SerialMessage,
+    BufStreamHandle,
 };
 use trust_dns_server::{
-    authority::{MessageRequest, MessageResponseBuilder},
+    authority::{MessageRequest, MessageResponse, MessageResponseBuilder},
     server::{Request, RequestHandler, ResponseHandler},
     ServerFuture,
 };
 
-use crate::setting::Setting;
+use crate::{
+    cache::DnsCache,
+    geoip::GeoIp,
+    hosts::{Hosts, Target},
+    metrics,
+    pool::IpPool,
+    querylog::{Entry, QueryLog},
+    ratelimit::RateLimiter,
+    rule::{self, CidrMatcher, DomainMatcher},
+    setting::{Reject, Rule, Setting},
+    upstream::{self, Answer, Upstream},
+};
 
-pub async fn serve(setting: Arc<Setting>, runtime: Arc<Runtime>) -> Result<(), String> {
-    let dns_upstream = &setting.clone().dns_upstream;
-    let resolver = create_resolver(dns_upstream, runtime.clone()).await?;
+pub async fn serve(
+    setting: Arc<Setting>,
+    runtime: Arc<Runtime>,
+    pool: Arc<Mutex<IpPool>>,
+) -> Result<(), String> {
+    let opt = Arc::new(DnsServerOpt::new(setting.clone(), &runtime, pool)?);
 
-    let dns_fallback = &setting.clone().dns_fallback;
-    let resolver_fallback = create_resolver(dns_fallback, runtime.clone()).await?;
+    // the handler of udp requests truncates the answers too large for them
+    let udp_server = Arc::new(DnsServer::new(opt.clone(), true));
+    let mut udp_joins = vec![];
+    let mut tcp_server = ServerFuture::new(DnsServer::new(opt, false));
+    let timeout = Duration::from_secs(setting.dns_tcp_timeout as u64);
+    for addr in setting.dns_listen_addrs()? {
+        let socket = bind_udp(addr).map_err(|e| format!("listen dns {}, err: {:?}", addr, e))?;
+        udp_joins.push(runtime.spawn(serve_udp(udp_server.clone(), socket)));
 
-    let opt = DnsServerOpt {
-        setting: setting.clone(),
-        resolver: Arc::new(resolver),
-        resolver_fallback: Arc::new(resolver_fallback),
+        // clients retry over tcp when the udp answer is truncated
+        let listener =
+            bind_tcp(addr).map_err(|e| format!("listen dns tcp {}, err: {:?}", addr, e))?;
+        tcp_server
+            .register_listener(listener, timeout, &runtime)
+            .map_err(|e| format!("listen dns tcp {}, err: {:?}", addr, e))?;
+        debug!("dns server listen on {}", addr);
+    }
+    debug!("dns server start");
+    let udp = async {
+        for res in try_join_all(udp_joins).await.map_err(|e| e.to_string())? {
+            res.map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    };
+    let tcp = async {
+        tcp_server
+            .block_until_done()
+            .await
+            .map_err(|e| e.to_string())
     };
+    try_join(udp, tcp).await.map(|_| ())
+}
+
+/// Serves the udp requests of `socket`, as `ServerFuture` does, but with
+/// `UdpResponseHandle` to send the responses.
+async fn serve_udp(server: Arc<DnsServer>, socket: UdpSocket) -> Result<(), ProtoError> {
+    let (mut stream, stream_handle) = UdpStream::with_bound(socket);
+    while let Some(message) = stream.next().await {
+        let message = match message {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("receive dns request error: {}", e);
+                continue;
+            }
+        };
+        let src = message.addr();
+        let request = match MessageRequest::read(&mut BinDecoder::new(message.bytes())) {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("invalid dns request from {}, err: {}", src, e);
+                continue;
+            }
+        };
+        let response_handle = UdpResponseHandle {
+            dst: src,
+            stream_handle: stream_handle.clone(),
+            max_payload: request.max_payload(),
+        };
+        let request = Request {
+            message: request,
+            src,
+        };
+        tokio::spawn(server.handle_request(request, response_handle));
+    }
+    Err(ProtoError::from("unexpected close of udp socket"))
+}
 
-    let handler = DnsServer::new(Arc::new(opt));
+/// Sends a udp response of at most `max_payload` bytes. Unlike
+/// `ResponseHandle`, it keeps the TC bit set on the response header, not
+/// only when records are left out for the size.
+#[derive(Clone)]
+struct UdpResponseHandle {
+    dst: SocketAddr,
+    stream_handle: BufStreamHandle,
+    max_payload: u16,
+}
 
-    let mut server = ServerFuture::new(handler);
-    let server_addr = format!("0.0.0.0:{}", setting.dns_port);
-    let socket = UdpSocket::bind(server_addr)
-        .await
-        .map_err(|e| format!("listen dns server, err: {:?}", e))?;
-    server.register_socket(socket, &runtime);
-    debug!("dns server start");
-    Ok(server
-        .block_until_done()
-        .await
-        .map_err(|e| format!("{}", e))?)
+impl ResponseHandler for UdpResponseHandle {
+    fn send_response(&self, response: MessageResponse) -> io::Result<()> {
+        let buffer = encode_response(response, self.max_payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        self.stream_handle
+            .unbounded_send(SerialMessage::new(buffer, self.dst))
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "dns udp socket closed"))
+    }
 }
 
-async fn create_resolver(hosts: &Vec<String>, runtime: Arc<Runtime>) -> Result<Resolver, String> {
-    let handle = runtime.handle().to_owned();
-    let mut ips = vec![];
-    for host in hosts {
-        let ip: IpAddr = host
-            .parse()
-            .map_err(|e| format!("invalid dns host: {}, err: {:?}", host, e))?;
-        ips.push(ip);
+/// Encodes `response` in at most `max_size` bytes. The encoding sets the
+/// TC bit only when it leaves records out, a truncated header is set again
+/// after it.
+fn encode_response(response: MessageResponse, max_size: u16) -> ProtoResult<Vec<u8>> {
+    let truncated = response.header().truncated();
+    let mut buffer = Vec::with_capacity(512);
+    let mut encoder = BinEncoder::new(&mut buffer);
+    encoder.set_max_size(max_size);
+    response.destructive_emit(&mut encoder)?;
+
+    let mut header = Header::read(&mut BinDecoder::new(&buffer))?;
+    if truncated && !header.truncated() {
+        header.set_truncated(true);
+        let mut bytes = Vec::with_capacity(12);
+        header.emit(&mut BinEncoder::new(&mut bytes))?;
+        buffer[..bytes.len()].copy_from_slice(&bytes);
     }
-    let name_server_group = NameServerConfigGroup::from_ips_clear(&ips, 53);
-    let config = ResolverConfig::from_parts(None, vec![], name_server_group);
-    let mut options = ResolverOpts::default();
-    options.cache_size = 1024;
-    TokioAsyncResolver::new(config, options, handle)
-        .await
-        .map_err(|e| format!("create resolver failed: {:?}", e))
+    Ok(buffer)
 }
 
-type Resolver = AsyncResolver<GenericConnection, GenericConnectionProvider<TokioRuntime>>;
+fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = new_socket(addr, Type::dgram())?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into_udp_socket())
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = new_socket(addr, Type::stream())?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into_tcp_listener())
+}
+
+// ipv6 sockets are v6 only, so `[::]` and `0.0.0.0` can be listed together
+fn new_socket(addr: SocketAddr, socket_type: Type) -> io::Result<Socket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => Socket::new(Domain::ipv4(), socket_type, None)?,
+        SocketAddr::V6(_) => {
+            let socket = Socket::new(Domain::ipv6(), socket_type, None)?;
+            socket.set_only_v6(true)?;
+            socket
+        }
+    };
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+// limit of hosts alias chain
+const MAX_ALIAS_DEPTH: usize = 8;
 
 struct DnsServerOpt {
     setting: Arc<Setting>,
-    resolver: Arc<Resolver>,
-    resolver_fallback: Arc<Resolver>,
+    resolver: Upstream,
+    resolver_fallback: Option<Upstream>,
+    resolver_groups: HashMap<String, Upstream>,
+    pool: Arc<Mutex<IpPool>>,
+    cache: Option<Mutex<DnsCache>>,
+    query_log: Option<QueryLog>,
+    allow: Vec<IpNet>,
+    rate_limiter: Option<Mutex<RateLimiter>>,
+    domain_matcher: DomainMatcher,
+    cidr_matcher: CidrMatcher,
+    geoip: GeoIp,
+    hosts: Hosts,
+}
+
+impl DnsServerOpt {
+    fn new(
+        setting: Arc<Setting>,
+        runtime: &Runtime,
+        pool: Arc<Mutex<IpPool>>,
+    ) -> Result<Self, String> {
+        let timeout = Duration::from_secs(setting.dns_timeout as u64);
+        let bootstrap =
+            upstream::bootstrap(setting.dns_upstream.iter().chain(&setting.dns_fallback));
+        let resolver = Upstream::new(&setting.dns_upstream, timeout, runtime, &bootstrap)?;
+        let resolver_fallback = match setting.dns_fallback.is_empty() {
+            true => None,
+            false => Some(Upstream::new(
+                &setting.dns_fallback,
+                timeout,
+                runtime,
+                &bootstrap,
+            )?),
+        };
+        let mut resolver_groups = HashMap::new();
+        for group in &setting.dns_group {
+            let upstream = Upstream::group(group, timeout, runtime, &bootstrap)
+                .map_err(|e| format!("dns group {}: {}", group.name, e))?;
+            resolver_groups.insert(group.name.clone(), upstream);
+        }
+
+        let cache = match setting.dns_cache_size {
+            0 => None,
+            size => Some(Mutex::new(DnsCache::new(
+                size as usize,
+                setting.dns_cache_min_ttl as u32,
+                setting.dns_cache_max_ttl as u32,
+                setting.dns_cache_stale as u32,
+                setting.dns_cache_prefetch,
+            ))),
+        };
+        let query_log = match setting.dns_query_log.as_str() {
+            "" => None,
+            path => Some(QueryLog::open(path)?),
+        };
+        let allow = setting
+            .dns_allow
+            .iter()
+            .filter_map(|v| v.parse().ok())
+            .collect();
+        let rate_limiter = match setting.dns_rate_limit {
+            0 => None,
+            rate => {
+                let burst = match setting.dns_rate_burst {
+                    0 => rate,
+                    v => v,
+                };
+                Some(Mutex::new(RateLimiter::new(rate as u32, burst as u32)))
+            }
+        };
+        let domain_matcher = DomainMatcher::new(&setting.rules)?;
+        let cidr_matcher = CidrMatcher::new(&setting.rules)?;
+        let geoip = GeoIp::new(&setting.geoip, &setting.rules)?;
+        let hosts = Hosts::parse(&setting.hosts)?;
+
+        Ok(DnsServerOpt {
+            setting,
+            resolver,
+            resolver_fallback,
+            resolver_groups,
+            pool,
+            cache,
+            query_log,
+            allow,
+            rate_limiter,
+            domain_matcher,
+            cidr_matcher,
+            geoip,
+            hosts,
+        })
+    }
+
+    fn domain_rule(&self, name: &str) -> Option<&Rule> {
+        let index = self.domain_matcher.find(name)?;
+        Some(&self.setting.rules[index])
+    }
+
+    fn apply_domain_rule(&self, name: &str) -> Option<IpAddr> {
+        let rule = self
+            .domain_rule(name)
+            .filter(|v| !v.target.is_empty() && !rule::is_reject(&v.target))?;
+        debug!("domain {} match rule, target: {}", name, rule.target);
+        Some(self.network_ip(name, &rule.target))
+    }
+
+    /// Whether A queries of `name` are answered with a fake ip.
+    fn is_hijacked(&self, name: &str) -> bool {
+        self.domain_rule(name).map_or(false, |v| {
+            !v.target.is_empty() && !rule::is_reject(&v.target)
+        }) || self.pool.lock().unwrap().get(name).is_some()
+    }
+
+    fn is_rejected(&self, name: &str) -> bool {
+        self.domain_rule(name)
+            .map_or(false, |v| rule::is_reject(&v.target))
+    }
+
+    /// The resolver of the first domain rule matching `name` with an
+    /// upstream, otherwise the default one.
+    fn upstream(&self, name: &str) -> &Upstream {
+        self.domain_rule(name)
+            .and_then(|v| v.upstream.as_ref())
+            .and_then(|v| self.resolver_groups.get(v))
+            .unwrap_or(&self.resolver)
+    }
+
+    fn network_ip(&self, host: &str, target: &str) -> IpAddr {
+        let mut pool = self.pool.lock().unwrap();
+        IpAddr::V4(pool.allocate(host, target))
+    }
 }
 
 struct DnsServer {
     opt: Arc<DnsServerOpt>,
+    /// Serves udp requests, answers are limited to their payload size.
+    udp: bool,
 }
 
 impl DnsServer {
-    fn new(opt: Arc<DnsServerOpt>) -> Self {
-        DnsServer { opt }
+    fn new(opt: Arc<DnsServerOpt>, udp: bool) -> Self {
+        DnsServer { opt, udp }
     }
 }
 
@@ -92,20 +347,78 @@ impl RequestHandler for DnsServer {
         request: Request,
         response_handle: R,
     ) -> Self::ResponseFuture {
-        let handler = QueryHandler::new(self.opt.clone());
+        let client = request.src.ip();
+        let mut handler = QueryHandler::new(self.opt.clone(), client);
+        if self.udp {
+            handler.max_payload = Some(request.message.max_payload());
+        }
 
         let request_message = request.message;
-        if request_message.message_type() == MessageType::Query {
-            if request_message.op_code() == OpCode::Query {
-                let queries = request_message.queries();
-                if queries.len() > 0 {
-                    let query = &queries[0];
-                    if query.query_type() == RecordType::A {
-                        let name = query.name();
-                        self.apply_rule(name);
-                        return Box::pin(handler.query_upstream(request_message, response_handle));
+        // checked before any upstream work, not to be an open resolver
+        if !self.opt.allow.is_empty() && !self.opt.allow.iter().any(|v| v.contains(&client)) {
+            debug!("refuse dns client {}", client);
+            metrics::DNS_DENIED.inc();
+            handler.reply_error(&request_message, response_handle, ResponseCode::Refused);
+            return Box::pin(futures::future::ready(()));
+        }
+        if let Some(limiter) = &self.opt.rate_limiter {
+            if !limiter.lock().unwrap().allow(client, Instant::now()) {
+                metrics::DNS_RATE_LIMITED.inc();
+                return Box::pin(futures::future::ready(()));
+            }
+        }
+
+        if request_message.message_type() == MessageType::Query
+            && request_message.op_code() == OpCode::Query
+        {
+            let queries = request_message.queries();
+            if !queries.is_empty() {
+                let query = &queries[0];
+                let record_type = query.query_type();
+                if record_type == RecordType::ANY && self.opt.setting.dns_refuse_any {
+                    metrics::DNS_ANY_REFUSED.inc();
+                    handler.reply_error(&request_message, response_handle, ResponseCode::Refused);
+                    return Box::pin(futures::future::ready(()));
+                }
+                if record_type == RecordType::A || record_type == RecordType::AAAA {
+                    if let Some(target) = self.opt.hosts.get(&query.name().to_string()) {
+                        return Box::pin(handler.answer_hosts(
+                            request_message,
+                            response_handle,
+                            target.clone(),
+                        ));
+                    }
+                }
+
+                if self.opt.is_rejected(&query.name().to_string()) {
+                    return Box::pin(handler.answer_reject(request_message, response_handle));
+                }
+
+                // reverse lookups of fake ips never reach the upstream
+                if record_type == RecordType::PTR {
+                    if let Some(ip) = arpa_ipv4(&query.name().to_string()) {
+                        if self.opt.pool.lock().unwrap().contains(&ip) {
+                            return Box::pin(handler.answer_ptr(
+                                request_message,
+                                response_handle,
+                                ip,
+                            ));
+                        }
                     }
                 }
+
+                if record_type == RecordType::A {
+                    if let Some(ip) = self.apply_rule(query.name()) {
+                        return Box::pin(handler.answer_ip(request_message, response_handle, ip));
+                    }
+                }
+
+                // no ipv6 for hijacked names, clients fall back to the fake ipv4
+                if record_type == RecordType::AAAA
+                    && self.opt.is_hijacked(&query.name().to_string())
+                {
+                    return Box::pin(handler.answer_empty(request_message, response_handle));
+                }
             }
         }
         Box::pin(handler.query_upstream(request_message, response_handle))
@@ -113,77 +426,528 @@ impl RequestHandler for DnsServer {
 }
 
 impl DnsServer {
-    fn network_ip(&self, host: &str) -> IpAddr {
-        let networks = &self.opt.clone().setting.clone().network;
-
-        todo!();
-    }
-
-    fn apply_rule(&self, name: &LowerName) {
-        let rules = &self.opt.clone().setting.clone().rules;
-        let ip = self.network_ip("");
+    fn apply_rule(&self, name: &LowerName) -> Option<IpAddr> {
+        self.opt.apply_domain_rule(&name.to_string())
     }
 }
 
 struct QueryHandler {
     opt: Arc<DnsServerOpt>,
+    client: IpAddr,
+    start: Instant,
+    // what decided the answer and where it came from, for the query log,
+    // the domain rule of the name unless set
+    matched: Option<Matched>,
+    upstream: Option<String>,
+    // size limit of an udp answer
+    max_payload: Option<u16>,
+}
+
+enum Matched {
+    Rule(usize),
+    Hosts,
+    /// A fake ip leased before, with its target.
+    Lease(String),
 }
 
 impl QueryHandler {
-    fn new(opt: Arc<DnsServerOpt>) -> Self {
-        QueryHandler { opt }
+    fn new(opt: Arc<DnsServerOpt>, client: IpAddr) -> Self {
+        QueryHandler {
+            opt,
+            client,
+            start: Instant::now(),
+            matched: None,
+            upstream: None,
+            max_payload: None,
+        }
     }
 }
 
 impl QueryHandler {
-    async fn query_upstream<R: ResponseHandler>(self, request: MessageRequest, response_handle: R) {
+    async fn query_upstream<R: ResponseHandler>(
+        mut self,
+        request: MessageRequest,
+        response_handle: R,
+    ) {
         let queries = request.queries();
         let query = &queries[0];
-        let record_type = query.query_type();
         let name = query.name();
 
-        let res = self
+        let res = match self.lookup(query.original().clone()).await {
+            Ok((res, upstream)) => {
+                self.upstream = Some(upstream);
+                res
+            }
+            Err(_) => return self.reply_error(&request, response_handle, ResponseCode::ServFail),
+        };
+
+        if query.query_type() == RecordType::A && res.response_code() == ResponseCode::NoError {
+            let ips: Vec<Ipv4Addr> = res
+                .answers()
+                .iter()
+                .filter_map(|v| match v.rdata() {
+                    RData::A(ip) => Some(*ip),
+                    _ => None,
+                })
+                .collect();
+
+            if let Some(index) = self.cidr_rule(name, &ips) {
+                self.matched = Some(Matched::Rule(index));
+                let target = self.opt.setting.rules[index].target.clone();
+                if rule::is_reject(&target) {
+                    return self.answer_reject(request, response_handle).await;
+                }
+                let ip = self.opt.network_ip(&name.to_string(), &target);
+                return self.answer_ip(request, response_handle, ip).await;
+            }
+        }
+
+        self.reply_message(&request, response_handle, &res);
+    }
+
+    fn cidr_rule(&self, name: &LowerName, ips: &[Ipv4Addr]) -> Option<usize> {
+        let index = match (self.opt.cidr_matcher.find(ips), self.opt.geoip.find(ips)) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => a.or(b)?,
+        };
+        let rule = &self.opt.setting.rules[index];
+        debug!(
+            "domain {} resolved {:?} match {:?} rule, target: {}",
+            name, ips, rule.rule_type, rule.target
+        );
+        Some(index)
+    }
+
+    async fn answer_reject<R: ResponseHandler>(self, request: MessageRequest, response_handle: R) {
+        metrics::DNS_REJECTED.inc();
+        let query = &request.queries()[0];
+        let name = query.original().name().clone();
+        let ip = match (&self.opt.setting.dns_reject, query.query_type()) {
+            (Reject::NxDomain, _) => {
+                return self.reply_error(&request, response_handle, ResponseCode::NXDomain)
+            }
+            (Reject::Refused, _) => {
+                return self.reply_error(&request, response_handle, ResponseCode::Refused)
+            }
+            (_, RecordType::A) => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            (_, RecordType::AAAA) => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            _ => None,
+        };
+        let answers: Vec<Record> = ip
+            .into_iter()
+            .map(|v| self.ip_record(name.clone(), v))
+            .collect();
+        self.reply(&request, response_handle, &answers);
+    }
+
+    /// Answers no records, for the AAAA queries of hijacked names.
+    async fn answer_empty<R: ResponseHandler>(
+        mut self,
+        request: MessageRequest,
+        response_handle: R,
+    ) {
+        let name = request.queries()[0].name().to_string();
+        let hijacked = self
             .opt
-            .clone()
-            .resolver
-            .clone()
-            .lookup(name, record_type, Default::default())
-            .await
-            .unwrap();
+            .domain_rule(&name)
+            .map_or(false, |v| !v.target.is_empty());
+        if !hijacked {
+            let mut pool = self.opt.pool.lock().unwrap();
+            let lease = pool.get(&name).and_then(|ip| pool.lookup(&ip));
+            self.matched = lease.map(|v| Matched::Lease(v.target.clone()));
+        }
+        self.reply(&request, response_handle, &[]);
+    }
+
+    async fn answer_ptr<R: ResponseHandler>(
+        mut self,
+        request: MessageRequest,
+        response_handle: R,
+        ip: Ipv4Addr,
+    ) {
+        let lease = match self.opt.pool.lock().unwrap().lookup(&ip) {
+            Some(v) => (v.domain.clone(), v.target.clone()),
+            None => return self.reply_error(&request, response_handle, ResponseCode::NXDomain),
+        };
+        let (domain, target) = lease;
+        self.matched = Some(Matched::Lease(target));
+        let domain = match Name::from_str(&domain) {
+            Ok(v) => v.append_domain(&Name::root()),
+            Err(_) => return self.reply_error(&request, response_handle, ResponseCode::NXDomain),
+        };
+
+        let query = &request.queries()[0];
+        let name = query.original().name().clone();
+        let ttl = self.opt.setting.dns_ttl as u32;
+        let answers = [Record::from_rdata(name, ttl, RData::PTR(domain))];
 
-        println!("res: {:?}", res);
+        self.reply(&request, response_handle, &answers);
+    }
+
+    async fn answer_ip<R: ResponseHandler>(
+        self,
+        request: MessageRequest,
+        response_handle: R,
+        ip: IpAddr,
+    ) {
+        let query = &request.queries()[0];
+        let name = query.original().name().clone();
+        let answers = [self.ip_record(name, ip)];
+
+        self.reply(&request, response_handle, &answers);
+    }
 
+    async fn answer_hosts<R: ResponseHandler>(
+        mut self,
+        request: MessageRequest,
+        response_handle: R,
+        target: Target,
+    ) {
+        let query = &request.queries()[0];
+        let record_type = query.query_type();
+        let ttl = self.opt.setting.dns_ttl as u32;
+        let mut name = query.original().name().clone();
+        let mut target = target;
         let mut answers = vec![];
-        let _name_servers: Vec<&Record> = vec![];
-        let _soa: Vec<&Record> = vec![];
-        let _additionals: Vec<&Record> = vec![];
-        for x in res.record_iter() {
-            match x.record_type() {
-                RecordType::A => answers.push(x),
-                _ => {}
+        self.matched = Some(Matched::Hosts);
+
+        for _ in 0..MAX_ALIAS_DEPTH {
+            let alias = match target {
+                Target::Ips(ips) => {
+                    let ips = ips.into_iter().filter(|v| match record_type {
+                        RecordType::A => v.is_ipv4(),
+                        _ => v.is_ipv6(),
+                    });
+                    for ip in ips {
+                        answers.push(self.ip_record(name.clone(), ip));
+                    }
+                    break;
+                }
+                Target::Alias(alias) => alias,
+            };
+
+            answers.push(Record::from_rdata(name, ttl, RData::CNAME(alias.clone())));
+            name = alias;
+
+            let host = name.to_string();
+            if let Some(v) = self.opt.hosts.get(&host) {
+                target = v.clone();
+                continue;
+            }
+
+            if self.opt.is_rejected(&host) {
+                break;
+            }
+            if record_type == RecordType::A {
+                if let Some(ip) = self.opt.apply_domain_rule(&host) {
+                    answers.push(self.ip_record(name, ip));
+                    break;
+                }
+            } else if self.opt.is_hijacked(&host) {
+                break;
             }
+
+            match self.lookup(Query::query(name, record_type)).await {
+                Ok((res, upstream)) => {
+                    self.upstream = Some(upstream);
+                    answers.extend(res.answers().iter().cloned());
+                }
+                Err(e) => debug!("resolve hosts alias {} failed, err: {}", host, e),
+            }
+            break;
         }
 
-        let mut header = Header::new();
-        header.set_id(request.id());
-        header.set_op_code(request.op_code());
-        header.set_message_type(MessageType::Response);
+        self.reply(&request, response_handle, &answers);
+    }
+
+    /// Answers from the cache, resolving and caching on a miss. An expired
+    /// entry is served when the resolution fails.
+    async fn lookup(&self, query: Query) -> Result<Answer, ProtoError> {
+        let subnet = self
+            .opt
+            .upstream(&query.name().to_string())
+            .subnet(self.client);
+        let cache = match &self.opt.cache {
+            Some(v) => v,
+            None => return self.resolve(query, subnet).await,
+        };
+
+        let cached = cache.lock().unwrap().get(&query, subnet, Instant::now());
+        if let Some((res, prefetch)) = cached {
+            metrics::DNS_CACHE_HITS.inc();
+            if prefetch {
+                metrics::DNS_CACHE_PREFETCH.inc();
+                let handler = QueryHandler::new(self.opt.clone(), self.client);
+                tokio::spawn(async move { handler.prefetch(query, subnet).await });
+            }
+            return Ok((res, "cache".to_string()));
+        }
+        metrics::DNS_CACHE_MISSES.inc();
+
+        let res = self.resolve(query.clone(), subnet).await;
+        let mut cache = cache.lock().unwrap();
+        match &res {
+            Ok((res, upstream)) if res.response_code() != ResponseCode::ServFail => {
+                cache.insert(&query, subnet, res, Instant::now());
+                return Ok((cache.clamp(res), upstream.clone()));
+            }
+            _ => {
+                if let Some(res) = cache.stale(&query, subnet, Instant::now()) {
+                    debug!("lookup {} {} serve stale", query.name(), query.query_type());
+                    metrics::DNS_CACHE_STALE.inc();
+                    return Ok((res, "stale".to_string()));
+                }
+            }
+        }
+        res
+    }
+
+    async fn prefetch(self, query: Query, subnet: Option<IpNet>) {
+        if let Ok((res, _)) = self.resolve(query.clone(), subnet).await {
+            if res.response_code() != ResponseCode::ServFail {
+                if let Some(cache) = &self.opt.cache {
+                    let mut cache = cache.lock().unwrap();
+                    cache.insert(&query, subnet, &res, Instant::now());
+                }
+            }
+        }
+    }
+
+    /// Looks up the upstream chosen by the domain rules, retrying against
+    /// the fallback, if any, on failure, timeout or SERVFAIL. A negative
+    /// answer is not a failure. `subnet` is only sent to the chosen upstream.
+    async fn resolve(&self, query: Query, subnet: Option<IpNet>) -> Result<Answer, ProtoError> {
+        let upstream = self.opt.upstream(&query.name().to_string());
+        let res = upstream.lookup(query.clone(), subnet).await;
+        match &res {
+            Ok((v, _)) if v.response_code() != ResponseCode::ServFail => return res,
+            Ok(_) => debug!("lookup {} {} servfail", query.name(), query.query_type()),
+            Err(e) => debug!(
+                "lookup {} {} failed, err: {}",
+                query.name(),
+                query.query_type(),
+                e
+            ),
+        }
+        metrics::DNS_UPSTREAM_ERRORS.inc();
+        let fallback = match &self.opt.resolver_fallback {
+            Some(v) => v,
+            None => return res,
+        };
+        metrics::DNS_FALLBACK.inc();
+
+        let res = fallback.lookup(query.clone(), None).await;
+        match &res {
+            Ok((res, _)) if res.response_code() != ResponseCode::ServFail => {}
+            _ => {
+                warn!(
+                    "lookup {} {} fallback failed",
+                    query.name(),
+                    query.query_type()
+                );
+                metrics::DNS_FALLBACK_ERRORS.inc();
+            }
+        }
+        res
+    }
+
+    fn ip_record(&self, name: Name, ip: IpAddr) -> Record {
+        let ttl = self.opt.setting.dns_ttl as u32;
+        let rdata = match ip {
+            IpAddr::V4(ip) => RData::A(ip),
+            IpAddr::V6(ip) => RData::AAAA(ip),
+        };
+        Record::from_rdata(name, ttl, rdata)
+    }
+
+    fn reply<R: ResponseHandler>(
+        &self,
+        request: &MessageRequest,
+        response_handle: R,
+        answers: &[Record],
+    ) {
+        self.log(request, ResponseCode::NoError, answers);
+        respond(request, response_handle, answers);
+    }
+
+    fn reply_message<R: ResponseHandler>(
+        &self,
+        request: &MessageRequest,
+        response_handle: R,
+        message: &Message,
+    ) {
+        self.log(request, message.response_code(), message.answers());
+        let minimal = self.opt.setting.dns_minimal_responses;
+        respond_message(request, response_handle, message, minimal, self.max_payload);
+    }
+
+    fn reply_error<R: ResponseHandler>(
+        &self,
+        request: &MessageRequest,
+        response_handle: R,
+        response_code: ResponseCode,
+    ) {
+        self.log(request, response_code, &[]);
+        respond_error(request, response_handle, response_code);
+    }
+
+    fn log(&self, request: &MessageRequest, response_code: ResponseCode, answers: &[Record]) {
+        let log = match &self.opt.query_log {
+            Some(v) => v,
+            None => return,
+        };
+        let query = match request.queries().first() {
+            Some(v) => v,
+            None => return,
+        };
+
+        let name = query.name().to_string();
+        let answers = answers.iter().filter_map(|v| match v.rdata() {
+            RData::A(ip) => Some(IpAddr::V4(*ip)),
+            RData::AAAA(ip) => Some(IpAddr::V6(*ip)),
+            _ => None,
+        });
+        let mut entry = Entry {
+            client: self.client.to_string(),
+            query_type: query.query_type().to_string(),
+            answers: answers.collect(),
+            upstream: self.upstream.clone(),
+            latency_ms: self.start.elapsed().as_micros() as f64 / 1000.0,
+            rcode: format!("{:?}", response_code),
+            ..Entry::default()
+        };
+
+        let index = match &self.matched {
+            Some(Matched::Rule(index)) => Some(*index),
+            Some(Matched::Hosts) => {
+                entry.rule = Some("hosts".to_string());
+                None
+            }
+            Some(Matched::Lease(target)) => {
+                entry.rule = Some("pool".to_string());
+                entry.target = Some(target.clone());
+                None
+            }
+            None => self.opt.domain_matcher.find(&name),
+        };
+        if let Some(index) = index {
+            let rule = &self.opt.setting.rules[index];
+            entry.rule = Some(rule.rule_type.name().to_string());
+            entry.rule_index = Some(index);
+            if !rule.target.is_empty() {
+                entry.target = Some(rule.target.clone());
+            }
+        }
+        entry.name = name;
+        log.write(entry);
+    }
+}
 
-        let builder = MessageResponseBuilder::new(Some(request.raw_queries()));
+fn respond<R: ResponseHandler>(request: &MessageRequest, response_handle: R, answers: &[Record]) {
+    let header = response_header(request);
+    let builder = MessageResponseBuilder::new(Some(request.raw_queries()));
+    let response = builder.build(
+        header,
+        records(answers),
+        records(&[]),
+        records(&[]),
+        records(&[]),
+    );
+    let _ = response_handle.send_response(response);
+}
 
-        let a = res.record_iter();
-        let mut list = vec![];
-        for x in a {
-            list.push(x);
+/// Passes through an upstream response, keeping its response code, TC bit
+/// and authority and additional sections. A minimal response keeps only
+/// the answers, and the authority of a negative one for its SOA.
+///
+/// A response larger than `max_payload` is sent truncated, with the TC bit
+/// and no records, for the client to retry over tcp.
+fn respond_message<R: ResponseHandler>(
+    request: &MessageRequest,
+    response_handle: R,
+    message: &Message,
+    minimal: bool,
+    max_payload: Option<u16>,
+) {
+    let mut header = response_header(request);
+    header.set_response_code(message.response_code());
+    header.set_authentic_data(message.authentic_data());
+    let mut truncated = message.truncated();
+    let (mut answers, mut name_servers, mut additionals) = match minimal {
+        true if !message.answers().is_empty() => (message.answers(), &[][..], &[][..]),
+        true => (message.answers(), message.name_servers(), &[][..]),
+        false => (
+            message.answers(),
+            message.name_servers(),
+            message.additionals(),
+        ),
+    };
+    if let Some(max_payload) = max_payload {
+        let mut response = Message::new();
+        for query in request.queries() {
+            response.add_query(query.original().clone());
         }
+        response.insert_answers(answers.to_vec());
+        response.insert_name_servers(name_servers.to_vec());
+        response.insert_additionals(additionals.to_vec());
+        let size = response.to_vec().map(|v| v.len()).unwrap_or(usize::MAX);
+        if size > max_payload as usize {
+            debug!("truncate dns response of {} bytes", size);
+            answers = &[];
+            name_servers = &[];
+            additionals = &[];
+            truncated = true;
+        }
+    }
+    header.set_truncated(truncated);
+    let builder = MessageResponseBuilder::new(Some(request.raw_queries()));
+    let response = builder.build(
+        header,
+        records(answers),
+        records(name_servers),
+        records(&[]),
+        records(additionals),
+    );
+    let _ = response_handle.send_response(response);
+}
+
+fn respond_error<R: ResponseHandler>(
+    request: &MessageRequest,
+    response_handle: R,
+    response_code: ResponseCode,
+) {
+    let builder = MessageResponseBuilder::new(Some(request.raw_queries()));
+    let response = builder.error_msg(request.id(), request.op_code(), response_code);
+    let _ = response_handle.send_response(response);
+}
 
-        let answers = Box::new(answers.into_iter()) as Box<dyn Iterator<Item = &Record> + Send>;
-        let name_servers = Box::new([].iter()) as Box<dyn Iterator<Item = &Record> + Send>;
-        let soa = Box::new([].iter()) as Box<dyn Iterator<Item = &Record> + Send>;
-        let additionals = Box::new([].iter()) as Box<dyn Iterator<Item = &Record> + Send>;
-        let response = builder.build(header, answers, name_servers, soa, additionals);
-        let _ = response_handle.send_response(response);
+/// Parses the ip of a `d.c.b.a.in-addr.arpa.` name.
+fn arpa_ipv4(name: &str) -> Option<Ipv4Addr> {
+    let name = name.to_lowercase();
+    let name = name.trim_end_matches('.').strip_suffix(".in-addr.arpa")?;
+    let mut octets = [0u8; 4];
+    let mut labels = name.split('.');
+    for octet in octets.iter_mut().rev() {
+        *octet = labels.next()?.parse().ok()?;
     }
+    match labels.next() {
+        Some(_) => None,
+        None => Some(Ipv4Addr::from(octets)),
+    }
+}
+
+fn records(records: &[Record]) -> Box<dyn Iterator<Item = &Record> + Send + '_> {
+    Box::new(records.iter())
+}
+
+fn response_header(request: &MessageRequest) -> Header {
+    let mut header = Header::new();
+    header.set_id(request.id());
+    header.set_op_code(request.op_code());
+    header.set_message_type(MessageType::Response);
+    header.set_recursion_desired(request.recursion_desired());
+    header.set_recursion_available(true);
+    header
 }
 
 #[cfg(test)]
@@ -202,6 +966,7 @@ mod test {
     use std::str::FromStr;
     use trust_dns_client::client::{Client, SyncClient};
     use trust_dns_client::rr::{DNSClass, Name, RecordType};
+    use trust_dns_client::tcp::TcpClientConnection;
     use trust_dns_client::udp::UdpClientConnection;
 
     #[test]
@@ -227,4 +992,908 @@ mod test {
             },
         }
     }
+
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Mutex;
+    use std::{io, sync::Arc};
+    use tokio::runtime::Runtime;
+    use trust_dns_client::op::{Message, Query, ResponseCode};
+    use trust_dns_client::rr::rdata::{MX, SOA};
+    use trust_dns_client::rr::{RData, Record};
+    use trust_dns_client::serialize::binary::{BinDecodable, BinDecoder};
+    use trust_dns_proto::op::header::MessageType;
+    use trust_dns_server::authority::{MessageRequest, MessageResponse};
+    use trust_dns_server::server::{Request, RequestHandler, ResponseHandler};
+
+    use super::{encode_response, DnsServer, DnsServerOpt};
+    use crate::{metrics, pool::IpPool, setting::Setting};
+
+    #[derive(Clone, Default)]
+    struct TestResponseHandler(Arc<Mutex<Vec<Message>>>);
+
+    impl ResponseHandler for TestResponseHandler {
+        fn send_response(&self, response: MessageResponse) -> io::Result<()> {
+            let buffer = encode_response(response, u16::MAX)?;
+            let message = Message::from_vec(&buffer)?;
+            self.0.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    const TEST_CONFIG: &str = r#"
+dns_upstream:
+  - UPSTREAM
+dns_fallback:
+  - FALLBACK
+dns_timeout: 1
+network:
+  - 10.85.0.1/16
+proxy:
+  - name: v2ray_hk
+    values: [socks5://127.0.0.1:1082]
+  - name: v2ray_jp
+    values: [socks5://127.0.0.1:1084]
+hosts: |
+  192.168.1.20                  myapp.com      # 我的app
+  cdn.myapp.com.a.bdydns.com.   cdn.myapp.com  # 我的app CDN
+  www.google.com                g.myapp.com
+rules:
+  - type: domain
+    target: v2ray_hk
+    values:
+      - "*.google.com"
+  - type: dnsCidr
+    target: v2ray_jp
+    values:
+      - 39.156.69.79/32
+"#;
+
+    fn test_upstream<F>(handler: F) -> SocketAddr
+    where
+        F: Fn(&Message) -> Option<Message> + Send + 'static,
+    {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok((n, src)) = socket.recv_from(&mut buf) {
+                let request = match Message::from_vec(&buf[..n]) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                if let Some(response) = handler(&request) {
+                    let _ = socket.send_to(&response.to_vec().unwrap(), src);
+                }
+            }
+        });
+        addr
+    }
+
+    fn test_response(request: &Message, answers: Vec<Record>) -> Message {
+        let mut message = Message::new();
+        message.set_id(request.id());
+        message.set_message_type(MessageType::Response);
+        message.set_recursion_desired(true);
+        message.set_recursion_available(true);
+        message.add_queries(request.queries().to_vec());
+        message.insert_answers(answers);
+        message
+    }
+
+    fn test_a_upstream() -> SocketAddr {
+        test_upstream(|request| {
+            let query = &request.queries()[0];
+            let name = query.name().clone();
+            if name.to_string() == "nx.example.com." {
+                let soa = SOA::new(
+                    Name::from_str("ns.example.com.").unwrap(),
+                    Name::from_str("admin.example.com.").unwrap(),
+                    1,
+                    3600,
+                    600,
+                    86400,
+                    60,
+                );
+                let record = Record::from_rdata(
+                    Name::from_str("example.com.").unwrap(),
+                    60,
+                    RData::SOA(soa),
+                );
+                let mut response = test_response(request, vec![]);
+                response.set_response_code(ResponseCode::NXDomain);
+                response.insert_name_servers(vec![record]);
+                return Some(response);
+            }
+
+            let rdata = match query.query_type() {
+                RecordType::AAAA => RData::AAAA("2001:db8::1".parse().unwrap()),
+                RecordType::MX => RData::MX(MX::new(10, Name::from_str("mx.qq.com.").unwrap())),
+                _ => match name.to_string().as_str() {
+                    "www.baidu.com." => RData::A(Ipv4Addr::new(39, 156, 69, 79)),
+                    _ => RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+                },
+            };
+            let record = Record::from_rdata(name, 300, rdata);
+            Some(test_response(request, vec![record]))
+        })
+    }
+
+    fn test_server(rt: &Arc<Runtime>, yaml: &str, upstream: SocketAddr) -> DnsServer {
+        test_server_with_fallback(rt, yaml, upstream, upstream)
+    }
+
+    fn test_server_with_fallback(
+        rt: &Arc<Runtime>,
+        yaml: &str,
+        upstream: SocketAddr,
+        fallback: SocketAddr,
+    ) -> DnsServer {
+        let yaml = yaml
+            .replace("UPSTREAM", &upstream.to_string())
+            .replace("FALLBACK", &fallback.to_string());
+        let setting = Setting::from_yaml(&yaml).unwrap();
+        let pool = Arc::new(Mutex::new(IpPool::load(&setting).unwrap()));
+        let opt = rt
+            .handle()
+            .block_on(async { DnsServerOpt::new(setting, rt, pool) })
+            .unwrap();
+        DnsServer::new(Arc::new(opt), true)
+    }
+
+    fn test_query(rt: &Arc<Runtime>, server: &DnsServer, name: &str, t: RecordType) -> Message {
+        let src = SocketAddr::from(([127, 0, 0, 1], 10053));
+        test_query_from(rt, server, src, name, t)
+    }
+
+    fn test_query_from(
+        rt: &Arc<Runtime>,
+        server: &DnsServer,
+        src: SocketAddr,
+        name: &str,
+        t: RecordType,
+    ) -> Message {
+        let request = test_request(src, name, t);
+
+        let handler = TestResponseHandler::default();
+        rt.handle()
+            .block_on(server.handle_request(request, handler.clone()));
+        let mut messages = handler.0.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        messages.remove(0)
+    }
+
+    fn test_request(src: SocketAddr, name: &str, t: RecordType) -> Request {
+        let mut message = Message::new();
+        message.set_id(1024);
+        message.set_recursion_desired(true);
+        message.add_query(Query::query(Name::from_str(name).unwrap(), t));
+        let buffer = message.to_vec().unwrap();
+        let mut decoder = BinDecoder::new(&buffer);
+        Request {
+            message: MessageRequest::read(&mut decoder).unwrap(),
+            src,
+        }
+    }
+
+    #[test]
+    fn test_hijack() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let server = test_server(&rt, TEST_CONFIG, test_a_upstream());
+
+        let res = test_query(&rt, &server, "www.google.com.", RecordType::A);
+        assert_eq!(res.id(), 1024);
+        assert_eq!(res.answers().len(), 1);
+        let answer = &res.answers()[0];
+        assert_eq!(answer.ttl(), 10);
+        assert_eq!(answer.name(), &Name::from_str("www.google.com.").unwrap());
+        let ip = match answer.rdata() {
+            RData::A(ip) => *ip,
+            v => panic!("unexpected rdata {:?}", v),
+        };
+        assert!("10.85.0.0/16"
+            .parse::<ipnet::Ipv4Net>()
+            .unwrap()
+            .contains(&ip));
+
+        let res = test_query(&rt, &server, "www.google.com.", RecordType::A);
+        assert_eq!(res.answers()[0].rdata(), &RData::A(ip));
+    }
+
+    #[test]
+    fn test_dns_cidr() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let server = test_server(&rt, TEST_CONFIG, test_a_upstream());
+
+        let res = test_query(&rt, &server, "www.baidu.com.", RecordType::A);
+        assert_eq!(res.answers().len(), 1);
+        assert_eq!(res.answers()[0].ttl(), 10);
+        let ip = match res.answers()[0].rdata() {
+            RData::A(ip) => *ip,
+            v => panic!("unexpected rdata {:?}", v),
+        };
+        let mut pool = server.opt.pool.lock().unwrap();
+        let lease = pool.lookup(&ip).unwrap();
+        assert_eq!(lease.domain, "www.baidu.com");
+        assert_eq!(lease.target, "v2ray_jp");
+        drop(pool);
+
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        assert_eq!(res.answers().len(), 1);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::A(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[test]
+    fn test_hosts() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let server = test_server(&rt, TEST_CONFIG, test_a_upstream());
+
+        let res = test_query(&rt, &server, "myapp.com.", RecordType::A);
+        assert_eq!(res.answers().len(), 1);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::A(Ipv4Addr::new(192, 168, 1, 20))
+        );
+
+        let res = test_query(&rt, &server, "cdn.myapp.com.", RecordType::A);
+        let answers = res.answers();
+        assert_eq!(answers.len(), 2);
+        let alias = Name::from_str("cdn.myapp.com.a.bdydns.com.").unwrap();
+        assert_eq!(
+            answers[0].name(),
+            &Name::from_str("cdn.myapp.com.").unwrap()
+        );
+        assert_eq!(answers[0].rdata(), &RData::CNAME(alias.clone()));
+        assert_eq!(answers[1].name(), &alias);
+        assert_eq!(answers[1].rdata(), &RData::A(Ipv4Addr::new(1, 2, 3, 4)));
+
+        let res = test_query(&rt, &server, "g.myapp.com.", RecordType::A);
+        let answers = res.answers();
+        assert_eq!(answers.len(), 2);
+        match answers[1].rdata() {
+            RData::A(ip) => assert_eq!(ip.octets()[..2], [10, 85]),
+            v => panic!("unexpected rdata {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_fallback() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let dead = test_upstream(|_| None);
+        let server = test_server_with_fallback(&rt, TEST_CONFIG, dead, test_a_upstream());
+
+        let fallback = metrics::DNS_FALLBACK.get();
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::A(Ipv4Addr::new(1, 2, 3, 4))
+        );
+        assert!(metrics::DNS_FALLBACK.get() > fallback);
+
+        let server = test_server_with_fallback(&rt, TEST_CONFIG, dead, dead);
+        let errors = metrics::DNS_FALLBACK_ERRORS.get();
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        assert_eq!(res.response_code(), ResponseCode::ServFail);
+        assert!(res.answers().is_empty());
+        assert!(metrics::DNS_FALLBACK_ERRORS.get() > errors);
+
+        // no fallback, straight to SERVFAIL
+        let yaml = TEST_CONFIG.replace("dns_fallback:\n  - FALLBACK", "dns_fallback: []");
+        let server = test_server(&rt, &yaml, dead);
+        assert!(server.opt.resolver_fallback.is_none());
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        assert_eq!(res.response_code(), ResponseCode::ServFail);
+        assert!(res.answers().is_empty());
+        let server = test_server(&rt, &yaml, test_a_upstream());
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::A(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let server = test_server(&rt, TEST_CONFIG, test_a_upstream());
+
+        let res = test_query(&rt, &server, "nx.example.com.", RecordType::A);
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        assert!(res.answers().is_empty());
+        assert_eq!(res.name_servers().len(), 1);
+        assert_eq!(res.name_servers()[0].record_type(), RecordType::SOA);
+
+        let res = test_query(&rt, &server, "qq.com.", RecordType::MX);
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::MX(MX::new(10, Name::from_str("mx.qq.com.").unwrap()))
+        );
+
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::AAAA);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::AAAA("2001:db8::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_hijacked_aaaa() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let server = test_server(&rt, TEST_CONFIG, test_a_upstream());
+
+        let res = test_query(&rt, &server, "www.google.com.", RecordType::AAAA);
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert!(res.answers().is_empty());
+
+        // hijacked by dnsCidr once resolved
+        let res = test_query(&rt, &server, "www.baidu.com.", RecordType::AAAA);
+        assert_eq!(res.answers().len(), 1);
+        test_query(&rt, &server, "www.baidu.com.", RecordType::A);
+        let res = test_query(&rt, &server, "www.baidu.com.", RecordType::AAAA);
+        assert!(res.answers().is_empty());
+
+        let res = test_query(&rt, &server, "myapp.com.", RecordType::AAAA);
+        assert!(res.answers().is_empty());
+    }
+
+    #[test]
+    fn test_tcp() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let upstream = test_a_upstream();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let yaml = format!(
+            "dns_port: {}\ndns_listen: [127.0.0.1, \"[::1]:{}\"]\n{}",
+            port, port, TEST_CONFIG
+        )
+        .replace("UPSTREAM", &upstream.to_string())
+        .replace("FALLBACK", &upstream.to_string());
+        let setting = Setting::from_yaml(&yaml).unwrap();
+        let pool = Arc::new(Mutex::new(IpPool::load(&setting).unwrap()));
+        rt.spawn(super::serve(setting, rt.clone(), pool));
+
+        let name = Name::from_str("www.qq.com.").unwrap();
+        let mut addresses = vec![
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port)),
+        ];
+        for _ in 0..50 {
+            let address = match addresses.last() {
+                Some(v) => *v,
+                None => return,
+            };
+            let conn = TcpClientConnection::new(address).unwrap();
+            if let Ok(res) = SyncClient::new(conn).query(&name, DNSClass::IN, RecordType::A) {
+                assert_eq!(
+                    res.answers()[0].rdata(),
+                    &RData::A(Ipv4Addr::new(1, 2, 3, 4))
+                );
+                addresses.pop();
+                continue;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        panic!("no answer over tcp from {:?}", addresses);
+    }
+
+    #[test]
+    fn test_listen_error() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let used = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = used.local_addr().unwrap();
+        let yaml = format!("dns_listen: [\"{}\"]\n{}", addr, TEST_CONFIG)
+            .replace("UPSTREAM", "127.0.0.1")
+            .replace("FALLBACK", "127.0.0.1");
+        let setting = Setting::from_yaml(&yaml).unwrap();
+        let pool = Arc::new(Mutex::new(IpPool::load(&setting).unwrap()));
+        let err = rt
+            .handle()
+            .block_on(super::serve(setting, rt.clone(), pool))
+            .unwrap_err();
+        assert!(err.starts_with(&format!("listen dns {}, err:", addr)));
+
+        let yaml = format!("dns_listen: [localhost]\n{}", TEST_CONFIG);
+        assert!(Setting::from_yaml(&yaml).is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        use trust_dns_client::op::Edns;
+
+        let rt = Arc::new(Runtime::new().unwrap());
+        let upstream = test_upstream(|request| {
+            let query = &request.queries()[0];
+            let answers = (0..50)
+                .map(|i| {
+                    let rdata = RData::A(Ipv4Addr::new(1, 2, 3, i));
+                    Record::from_rdata(query.name().clone(), 300, rdata)
+                })
+                .collect();
+            Some(test_response(request, answers))
+        });
+        let server = test_server(&rt, TEST_CONFIG, upstream);
+
+        // over 512 bytes
+        let res = test_query(&rt, &server, "big.example.com.", RecordType::A);
+        assert!(res.truncated(), "{:?}", res);
+        assert!(res.answers().is_empty());
+
+        let src = SocketAddr::from(([127, 0, 0, 1], 10053));
+        let mut request = test_request(src, "big.example.com.", RecordType::A);
+        let mut message = Message::new();
+        message.set_id(1024);
+        message.add_query(Query::query(
+            Name::from_str("big.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let mut edns = Edns::new();
+        edns.set_max_payload(4096);
+        message.set_edns(edns);
+        let buffer = message.to_vec().unwrap();
+        request.message = MessageRequest::read(&mut BinDecoder::new(&buffer)).unwrap();
+        let handler = TestResponseHandler::default();
+        rt.handle()
+            .block_on(server.handle_request(request, handler.clone()));
+        let res = handler.0.lock().unwrap().remove(0);
+        assert!(!res.truncated());
+        assert_eq!(res.answers().len(), 50);
+
+        // over a udp socket
+        let socket = rt
+            .handle()
+            .block_on(tokio::net::UdpSocket::bind("127.0.0.1:0"))
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        let udp = Arc::new(DnsServer::new(server.opt.clone(), true));
+        rt.spawn(super::serve_udp(udp, socket));
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let mut message = Message::new();
+        message.set_id(1025);
+        message.add_query(Query::query(
+            Name::from_str("big.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        client.send_to(&message.to_vec().unwrap(), addr).unwrap();
+        let mut buf = [0u8; 4096];
+        let n = client.recv(&mut buf).unwrap();
+        assert!(n <= 512, "{}", n);
+        let res = Message::from_vec(&buf[..n]).unwrap();
+        assert_eq!(res.id(), 1025);
+        assert!(res.truncated(), "{:?}", res);
+        assert!(res.answers().is_empty());
+        assert_eq!(res.queries().len(), 1);
+
+        // no limit over tcp
+        let tcp = DnsServer::new(server.opt.clone(), false);
+        let res = test_query(&rt, &tcp, "big.example.com.", RecordType::A);
+        assert!(!res.truncated());
+        assert_eq!(res.answers().len(), 50);
+
+        let yaml = format!("dns_tcp_timeout: 0\n{}", TEST_CONFIG);
+        assert!(Setting::from_yaml(&yaml).is_err());
+    }
+
+    #[test]
+    fn test_upstream_group() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let office = test_upstream(|request| {
+            let query = &request.queries()[0];
+            let rdata = RData::A(Ipv4Addr::new(10, 0, 0, 1));
+            let record = Record::from_rdata(query.name().clone(), 300, rdata);
+            Some(test_response(request, vec![record]))
+        });
+        let yaml = TEST_CONFIG.replace(
+            "rules:\n",
+            &format!(
+                r#"dns_group:
+  - name: office
+    values:
+      - {}
+rules:
+  - type: domain
+    upstream: office
+    values:
+      - "*.corp.com"
+  - type: domain
+    target: v2ray_hk
+    upstream: office
+    values:
+      - "*.google.com.hk"
+"#,
+                office
+            ),
+        );
+        let server = test_server(&rt, &yaml, test_a_upstream());
+
+        let res = test_query(&rt, &server, "git.corp.com.", RecordType::A);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        let res = test_query(&rt, &server, "www.corp.org.", RecordType::A);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::A(Ipv4Addr::new(1, 2, 3, 4))
+        );
+
+        // hijacked, other types still resolved by the group
+        let res = test_query(&rt, &server, "www.google.com.hk.", RecordType::A);
+        match res.answers()[0].rdata() {
+            RData::A(ip) => assert_eq!(ip.octets()[..2], [10, 85]),
+            v => panic!("unexpected rdata {:?}", v),
+        }
+        let res = test_query(&rt, &server, "www.google.com.hk.", RecordType::TXT);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+
+        let err = Setting::from_yaml(&yaml.replace("upstream: office", "upstream: home"));
+        assert!(format!("{:?}", err.unwrap_err()).contains("unknown dns group: home"));
+        let err = Setting::from_yaml(&yaml.replace("    upstream: office\n", ""));
+        assert!(format!("{:?}", err.unwrap_err()).contains("missing target of Domain rule"));
+        let err = Setting::from_yaml(&yaml.replace(
+            "target: v2ray_jp\n",
+            "target: v2ray_jp\n    upstream: office\n",
+        ));
+        assert!(format!("{:?}", err.unwrap_err()).contains("upstream is not supported"));
+        let err = Setting::from_yaml(&yaml.replace(
+            "  - name: office\n",
+            "  - name: office\n    strategy: random\n",
+        ));
+        assert!(format!("{:?}", err.unwrap_err()).contains("unknown strategy of dns group office"));
+        let err = Setting::from_yaml(&yaml.replace(
+            "  - name: office\n",
+            "  - name: office\n    strategy: prefer-clean\n",
+        ));
+        assert!(format!("{:?}", err.unwrap_err()).contains("has no clean"));
+    }
+
+    #[test]
+    fn test_ptr() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let server = test_server(&rt, TEST_CONFIG, test_a_upstream());
+
+        let res = test_query(&rt, &server, "www.google.com.", RecordType::A);
+        let ip = match res.answers()[0].rdata() {
+            RData::A(ip) => *ip,
+            v => panic!("unexpected rdata {:?}", v),
+        };
+        let o = ip.octets();
+        let arpa = format!("{}.{}.{}.{}.in-addr.arpa.", o[3], o[2], o[1], o[0]);
+        assert_eq!(super::arpa_ipv4(&arpa), Some(ip));
+
+        let res = test_query(&rt, &server, &arpa, RecordType::PTR);
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::PTR(Name::from_str("www.google.com.").unwrap())
+        );
+
+        let res = test_query(&rt, &server, "254.255.85.10.in-addr.arpa.", RecordType::PTR);
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        assert!(res.answers().is_empty());
+
+        assert_eq!(super::arpa_ipv4("1.2.3.in-addr.arpa."), None);
+        assert_eq!(super::arpa_ipv4("5.1.2.3.4.in-addr.arpa."), None);
+    }
+
+    #[test]
+    fn test_reject() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let yaml = TEST_CONFIG.replace(
+            "rules:\n",
+            r#"rules:
+  - type: domain
+    target: reject
+    values:
+      - "*.ads.com"
+  - type: domain
+    target: block
+    values:
+      - tracker.net
+  - type: dnsCidr
+    target: reject
+    values:
+      - 1.2.3.4/32
+"#,
+        );
+        let server = test_server(&rt, &yaml, test_a_upstream());
+
+        let rejected = metrics::DNS_REJECTED.get();
+        let res = test_query(&rt, &server, "x.ads.com.", RecordType::A);
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        assert!(res.answers().is_empty());
+        let res = test_query(&rt, &server, "tracker.net.", RecordType::AAAA);
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        // upstream answers a rejected cidr
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        assert!(metrics::DNS_REJECTED.get() >= rejected + 3);
+        assert!(server.opt.pool.lock().unwrap().get("x.ads.com").is_none());
+
+        let res = test_query(&rt, &server, "www.baidu.com.", RecordType::A);
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+
+        let server = test_server(
+            &rt,
+            &format!("dns_reject: zero\n{}", yaml),
+            test_a_upstream(),
+        );
+        let res = test_query(&rt, &server, "x.ads.com.", RecordType::A);
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert_eq!(res.answers()[0].rdata(), &RData::A(Ipv4Addr::UNSPECIFIED));
+        let res = test_query(&rt, &server, "x.ads.com.", RecordType::AAAA);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::AAAA(std::net::Ipv6Addr::UNSPECIFIED)
+        );
+        let res = test_query(&rt, &server, "x.ads.com.", RecordType::MX);
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert!(res.answers().is_empty());
+
+        let server = test_server(
+            &rt,
+            &format!("dns_reject: refused\n{}", yaml),
+            test_a_upstream(),
+        );
+        let res = test_query(&rt, &server, "tracker.net.", RecordType::A);
+        assert_eq!(res.response_code(), ResponseCode::Refused);
+
+        let err = Setting::from_yaml(&format!("dns_reject: drop\n{}", yaml));
+        assert!(format!("{:?}", err.unwrap_err()).contains("unknown dns_reject"));
+    }
+
+    #[test]
+    fn test_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let rt = Arc::new(Runtime::new().unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let upstream = test_upstream(move |request| {
+            let query = &request.queries()[0];
+            if counter.fetch_add(1, Ordering::SeqCst) > 0 {
+                let mut response = test_response(request, vec![]);
+                response.set_response_code(ResponseCode::ServFail);
+                return Some(response);
+            }
+            let rdata = RData::A(Ipv4Addr::new(1, 2, 3, 4));
+            let record = Record::from_rdata(query.name().clone(), 300, rdata);
+            Some(test_response(request, vec![record]))
+        });
+        let yaml = format!(
+            "dns_cache_min_ttl: 1\ndns_cache_max_ttl: 1\ndns_cache_stale: 60\n{}",
+            TEST_CONFIG
+        );
+        let server = test_server(&rt, &yaml, upstream);
+
+        let (hits, misses) = (
+            metrics::DNS_CACHE_HITS.get(),
+            metrics::DNS_CACHE_MISSES.get(),
+        );
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        assert_eq!(res.answers()[0].ttl(), 1);
+        let res = test_query(&rt, &server, "WWW.QQ.COM.", RecordType::A);
+        assert_eq!(res.answers()[0].ttl(), 1);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::A(Ipv4Addr::new(1, 2, 3, 4))
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(metrics::DNS_CACHE_HITS.get() > hits);
+        assert!(metrics::DNS_CACHE_MISSES.get() > misses);
+
+        // expired, the upstream fails now
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        let stale = metrics::DNS_CACHE_STALE.get();
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert_eq!(res.answers()[0].ttl(), 30);
+        assert!(count.load(Ordering::SeqCst) > 1);
+        assert!(metrics::DNS_CACHE_STALE.get() > stale);
+
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::AAAA);
+        assert_eq!(res.response_code(), ResponseCode::ServFail);
+
+        let err = Setting::from_yaml(&yaml.replace("min_ttl: 1", "min_ttl: 600"));
+        assert!(format!("{:?}", err.unwrap_err()).contains("greater than dns_cache_max_ttl"));
+    }
+
+    #[test]
+    fn test_ecs() {
+        use trust_dns_client::rr::rdata::opt::{EdnsCode, EdnsOption};
+
+        let rt = Arc::new(Runtime::new().unwrap());
+        // answers with the address of the client subnet, 0.0.0.0 without
+        let cdn = test_upstream(|request| {
+            let query = &request.queries()[0];
+            let ip = match request.edns().and_then(|v| v.option(EdnsCode::Subnet)) {
+                Some(EdnsOption::Unknown(_, data)) if data.len() == 7 => {
+                    Ipv4Addr::new(data[4], data[5], data[6], 0)
+                }
+                _ => Ipv4Addr::UNSPECIFIED,
+            };
+            let record = Record::from_rdata(query.name().clone(), 300, RData::A(ip));
+            Some(test_response(request, vec![record]))
+        });
+        let yaml = TEST_CONFIG.replace(
+            "rules:\n",
+            &format!(
+                r#"dns_group:
+  - name: office
+    ecs: 203.0.113.0/24
+    values:
+      - {0}
+  - name: client
+    ecs: client
+    values:
+      - {0}
+rules:
+  - type: domain
+    upstream: office
+    values:
+      - "*.office.com"
+  - type: domain
+    upstream: client
+    values:
+      - "*.cdn.com"
+"#,
+                cdn
+            ),
+        );
+        let server = test_server(&rt, &yaml, cdn);
+
+        let res = test_query(&rt, &server, "www.office.com.", RecordType::A);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::A(Ipv4Addr::new(203, 0, 113, 0))
+        );
+        let src = SocketAddr::from(([198, 51, 100, 7], 10053));
+        let res = test_query_from(&rt, &server, src, "www.cdn.com.", RecordType::A);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::A(Ipv4Addr::new(198, 51, 100, 0))
+        );
+        // cached per subnet, a private client sends none
+        let res = test_query(&rt, &server, "www.cdn.com.", RecordType::A);
+        assert_eq!(res.answers()[0].rdata(), &RData::A(Ipv4Addr::UNSPECIFIED));
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        assert_eq!(res.answers()[0].rdata(), &RData::A(Ipv4Addr::UNSPECIFIED));
+
+        let err = Setting::from_yaml(&yaml.replace("ecs: client", "ecs: home"));
+        assert!(format!("{:?}", err.unwrap_err()).contains("invalid ecs of dns group client"));
+    }
+
+    #[test]
+    fn test_query_log() {
+        use crate::querylog::Entry;
+
+        let rt = Arc::new(Runtime::new().unwrap());
+        let path = std::env::temp_dir().join(format!("kungfu-dns-{}.log", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        let yaml = format!("dns_query_log: {}\n{}", path, TEST_CONFIG);
+        let server = test_server(&rt, &yaml, test_a_upstream());
+
+        test_query(&rt, &server, "www.google.com.", RecordType::A);
+        test_query(&rt, &server, "www.baidu.com.", RecordType::A);
+        test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        test_query(&rt, &server, "myapp.com.", RecordType::A);
+        test_query(&rt, &server, "nx.example.com.", RecordType::A);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let entries: Vec<Entry> = content
+            .lines()
+            .map(|v| serde_json::from_str(v).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 6);
+
+        let hijacked = &entries[0];
+        assert_eq!(hijacked.client, "127.0.0.1");
+        assert_eq!(hijacked.name, "www.google.com.");
+        assert_eq!(hijacked.query_type, "A");
+        assert_eq!(hijacked.rule.as_deref(), Some("domain"));
+        assert_eq!(hijacked.rule_index, Some(0));
+        assert_eq!(hijacked.target.as_deref(), Some("v2ray_hk"));
+        assert_eq!(hijacked.upstream, None);
+        assert_eq!(hijacked.rcode, "NoError");
+
+        let cidr = &entries[1];
+        assert_eq!(cidr.rule.as_deref(), Some("dnsCidr"));
+        assert_eq!(cidr.rule_index, Some(1));
+        assert_eq!(cidr.target.as_deref(), Some("v2ray_jp"));
+        assert!(cidr.upstream.as_ref().unwrap().starts_with("127.0.0.1:"));
+        assert_eq!(cidr.answers.len(), 1);
+
+        let passthrough = &entries[2];
+        assert_eq!(passthrough.rule, None);
+        assert_eq!(passthrough.target, None);
+        assert_eq!(passthrough.answers, vec![IpAddr::from([1, 2, 3, 4])]);
+        assert_eq!(entries[3].upstream.as_deref(), Some("cache"));
+
+        assert_eq!(entries[4].rule.as_deref(), Some("hosts"));
+        assert_eq!(entries[4].answers, vec![IpAddr::from([192, 168, 1, 20])]);
+        assert_eq!(entries[5].rcode, "NXDomain");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_abuse() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let upstream = test_upstream(|request| {
+            let query = &request.queries()[0];
+            let name = query.name().clone();
+            let ns = Name::from_str("ns.qq.com.").unwrap();
+            let record = Record::from_rdata(name.clone(), 300, RData::A(Ipv4Addr::new(1, 2, 3, 4)));
+            let mut response = test_response(request, vec![record]);
+            response.add_name_server(Record::from_rdata(name, 300, RData::NS(ns.clone())));
+            response.add_additional(Record::from_rdata(
+                ns,
+                300,
+                RData::A(Ipv4Addr::new(5, 6, 7, 8)),
+            ));
+            Some(response)
+        });
+        let yaml = format!(
+            "dns_allow:\n  - 127.0.0.0/8\n  - 198.51.100.0/24\ndns_rate_limit: 1\ndns_rate_burst: 3\n{}",
+            TEST_CONFIG
+        );
+        let server = test_server(&rt, &yaml, upstream);
+
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        assert_eq!(res.answers().len(), 1);
+        assert_eq!(res.name_servers().len(), 1);
+        assert_eq!(res.additionals().len(), 1);
+
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::ANY);
+        assert_eq!(res.response_code(), ResponseCode::Refused);
+
+        let denied = metrics::DNS_DENIED.get();
+        let src = SocketAddr::from(([203, 0, 113, 1], 10053));
+        let res = test_query_from(&rt, &server, src, "www.qq.com.", RecordType::A);
+        assert_eq!(res.response_code(), ResponseCode::Refused);
+        assert!(metrics::DNS_DENIED.get() > denied);
+
+        // dropped without a response once the burst of 3 is used up
+        let src = SocketAddr::from(([198, 51, 100, 1], 10053));
+        for _ in 0..3 {
+            let res = test_query_from(&rt, &server, src, "www.qq.com.", RecordType::A);
+            assert_eq!(res.response_code(), ResponseCode::NoError);
+        }
+        let limited = metrics::DNS_RATE_LIMITED.get();
+        let handler = TestResponseHandler::default();
+        let request = test_request(src, "www.qq.com.", RecordType::A);
+        rt.handle()
+            .block_on(server.handle_request(request, handler.clone()));
+        assert!(handler.0.lock().unwrap().is_empty());
+        assert!(metrics::DNS_RATE_LIMITED.get() > limited);
+
+        let yaml = format!(
+            "dns_minimal_responses: true\ndns_refuse_any: false\n{}",
+            TEST_CONFIG
+        );
+        let server = test_server(&rt, &yaml, upstream);
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::A);
+        assert_eq!(res.answers().len(), 1);
+        assert!(res.name_servers().is_empty());
+        assert!(res.additionals().is_empty());
+        let res = test_query(&rt, &server, "www.qq.com.", RecordType::ANY);
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+
+        let err = Setting::from_yaml(&yaml.replace("dns_refuse_any: false", "dns_allow: [lan]"));
+        assert!(format!("{:?}", err.unwrap_err()).contains("invalid dns_allow cidr"));
+    }
 }
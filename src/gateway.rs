@@ -1,31 +1,64 @@
 use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     process::{self, Command},
-    sync::{Arc, Once},
-    time::Instant,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Once,
+    },
+    time::{Duration, Instant},
 };
 
-use futures::{future::join_all, SinkExt, StreamExt};
+use futures::{
+    future::{join, join3, join_all, try_join},
+    SinkExt, StreamExt,
+};
 use icmp::destination_unreachable::IcmpCodes;
 use ipnet::Ipv4Net;
 use pnet::packet::{
     icmp::{self, IcmpTypes, MutableIcmpPacket},
     ip::IpNextHeaderProtocols,
-    ipv4::{self, MutableIpv4Packet},
-    udp::MutableUdpPacket,
-    Packet,
+    ipv4::{self, Ipv4Packet, MutableIpv4Packet},
+    tcp::{self, MutableTcpPacket, TcpFlags, TcpPacket},
+    udp::{self, MutableUdpPacket},
+    MutablePacket, Packet,
+};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
 };
 use tokio_util::codec::Framed;
 use tun::{AsyncDevice, Configuration, TunPacket, TunPacketCodec};
 
-use crate::setting::{RuleType, Setting};
+use crate::{
+    metrics,
+    nat::{self, Nat, Protocol},
+    pool::IpPool,
+    proxy::{self, Proxies},
+    rule,
+    setting::{Endpoint, RuleType, Setting},
+    socks5::{self, Address},
+};
 
-pub async fn serve(setting: Arc<Setting>) -> Result<(), String> {
+pub async fn serve(setting: Arc<Setting>, pool: Arc<Mutex<IpPool>>) -> Result<(), String> {
+    let nat = Arc::new(Mutex::new(Nat::new(NAT_CAPACITY)));
+    let proxies = Arc::new(Proxies::new(
+        &setting.proxy,
+        setting.proxy_check_rise as u32,
+        setting.proxy_check_fall as u32,
+    ));
     let mut gateways = vec![];
-    let mut id = 0;
-    for network in setting.network.iter() {
-        let gateway = Gateway::new(id, network, setting.clone());
+    for (id, network) in setting.network.iter().enumerate() {
+        let gateway = Gateway::new(
+            id as i32,
+            network,
+            setting.clone(),
+            pool.clone(),
+            nat.clone(),
+            proxies.clone(),
+        );
         gateways.push(gateway);
-        id += 1;
     }
 
     let mut handlers = vec![];
@@ -33,25 +66,96 @@ pub async fn serve(setting: Arc<Setting>) -> Result<(), String> {
         handlers.push(gateway.serve());
     }
 
-    join_all(handlers).await;
+    join3(
+        join_all(handlers),
+        sweep(nat),
+        proxy::check(proxies, setting.clone()),
+    )
+    .await;
 
     Ok(())
 }
 
+/// Expires the idle nat sessions, publishing the table size.
+async fn sweep(nat: Arc<Mutex<Nat>>) {
+    let mut interval = tokio::time::interval(NAT_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut nat = nat.lock().unwrap();
+        nat.expire(Instant::now());
+        let stats = nat.stats();
+        metrics::NAT_TCP_SESSIONS.set(stats.tcp as u64);
+        metrics::NAT_UDP_SESSIONS.set(stats.udp as u64);
+    }
+}
+
 struct Gateway {
     id: i32,
     net: Ipv4Net,
     setting: Arc<Setting>,
+    reject: Vec<Ipv4Net>,
+    /// Route rules relayed through their proxy, with the target.
+    routes: Vec<(Ipv4Net, String)>,
+    pool: Arc<Mutex<IpPool>>,
+    nat: Arc<Mutex<Nat>>,
+    proxies: Arc<Proxies>,
+    /// Udp flows by source and destination.
+    flows: Arc<Mutex<HashMap<(SocketAddrV4, SocketAddrV4), UdpFlow>>>,
 }
 
+struct UdpFlow {
+    id: u64,
+    sender: UnboundedSender<Vec<u8>>,
+}
+
+static NEXT_FLOW: AtomicU64 = AtomicU64::new(0);
+
 static ROUTE_RULE_ONCE: Once = Once::new();
 
 const MTU: usize = 1400;
 
+// sessions of all gateways
+const NAT_CAPACITY: usize = 32768;
+const NAT_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
 impl Gateway {
-    fn new(id: i32, network: &str, setting: Arc<Setting>) -> Self {
+    fn new(
+        id: i32,
+        network: &str,
+        setting: Arc<Setting>,
+        pool: Arc<Mutex<IpPool>>,
+        nat: Arc<Mutex<Nat>>,
+        proxies: Arc<Proxies>,
+    ) -> Self {
         let net = network.parse().unwrap();
-        Gateway { id, net, setting }
+        // route rules with a reject target, their traffic is dropped
+        let reject = setting
+            .rules
+            .iter()
+            .filter(|v| v.rule_type == RuleType::Route && rule::is_reject(&v.target))
+            .flat_map(|v| v.values.iter().filter_map(|v| v.parse().ok()))
+            .collect();
+        let routes = setting
+            .rules
+            .iter()
+            .filter(|v| v.rule_type == RuleType::Route && !rule::is_reject(&v.target))
+            .flat_map(|v| {
+                v.values
+                    .iter()
+                    .filter_map(move |net| Some((net.parse().ok()?, v.target.clone())))
+            })
+            .collect();
+        Gateway {
+            id,
+            net,
+            setting,
+            reject,
+            routes,
+            pool,
+            nat,
+            proxies,
+            flows: Arc::new(Mutex::new(HashMap::new())),
+        }
     }
 
     async fn serve(&self) {
@@ -94,7 +198,7 @@ impl Gateway {
             let net = format!("{}/{}", self.net.network(), self.net.prefix_len());
             debug!("for macOS manual add net route {}", net);
             let _ = Command::new("route")
-                .args(&[
+                .args([
                     "-n",
                     "-q",
                     "add",
@@ -107,20 +211,59 @@ impl Gateway {
 
         self.apply_rules();
 
+        // tcp connections are redirected to this listener, see `handle_tcp`
+        let listener = match TcpListener::bind((self.net.addr(), 0)).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("listen tcp relay ({}) failed, err: {:?}", self.id, e);
+                process::exit(1);
+            }
+        };
+        let relay_port = listener.local_addr().unwrap().port();
+        debug!("tcp relay ({}) listen on {}", self.id, relay_port);
+
+        join(self.serve_tun(dev, relay_port), self.accept(listener)).await;
+    }
+
+    async fn serve_tun(&self, dev: AsyncDevice, relay_port: u16) {
         let mut stream = dev.into_framed();
-        while let Some(packet) = stream.next().await {
+        // datagrams answered through the udp relays
+        let (tun, mut replies) = mpsc::unbounded_channel();
+        loop {
+            let packet = tokio::select! {
+                packet = stream.next() => match packet {
+                    Some(v) => v,
+                    None => break,
+                },
+                Some(reply) = replies.recv() => {
+                    let _ = stream.send(TunPacket::new(reply)).await;
+                    continue;
+                }
+            };
             match packet {
                 Ok(pkt) => {
                     let mut pkt = pkt.get_bytes().to_vec();
                     let mut packet = MutableIpv4Packet::new(&mut pkt).unwrap();
+                    let dst = packet.get_destination();
+                    if self.reject.iter().any(|v| v.contains(&dst)) {
+                        if let Some(reset) = tcp_reset(&packet.to_immutable()) {
+                            let _ = stream.send(TunPacket::new(reset)).await;
+                        }
+                        continue;
+                    }
                     let payload = packet.to_immutable().payload().to_vec();
                     match packet.get_next_level_protocol() {
                         IpNextHeaderProtocols::Icmp => {
                             self.handle_icmp(&mut packet, payload, &mut stream).await;
                         }
-                        IpNextHeaderProtocols::Tcp => {}
+                        IpNextHeaderProtocols::Tcp => {
+                            if self.handle_tcp(&mut packet, relay_port) {
+                                let _ = stream.send(TunPacket::new(pkt)).await;
+                            }
+                        }
                         IpNextHeaderProtocols::Udp => {
-                            self.handle_udp(&mut packet, payload, &mut stream).await;
+                            self.handle_udp(&mut packet, payload, &mut stream, &tun)
+                                .await;
                         }
                         _ => {}
                     }
@@ -145,14 +288,14 @@ impl Gateway {
                     #[cfg(target_os = "linux")]
                     {
                         let _ = Command::new("ip")
-                            .args(&["route", "add", v, "via", &self.net.addr().to_string()])
+                            .args(["route", "add", v, "via", &self.net.addr().to_string()])
                             .output();
                     }
 
                     #[cfg(target_os = "macos")]
                     {
                         let _ = Command::new("route")
-                            .args(&["-n", "-q", "add", "-net", v, &self.net.addr().to_string()])
+                            .args(["-n", "-q", "add", "-net", v, &self.net.addr().to_string()])
                             .output();
                     }
                 }
@@ -182,11 +325,139 @@ impl Gateway {
         let _ = stream.send(TunPacket::new(packet.packet().to_vec())).await;
     }
 
+    /// Redirects the tcp connections to fake ips and routed networks to the
+    /// relay listener at `relay_port`, rewriting the packet in place.
+    ///
+    /// A segment from `C:cp` to `D:dp` becomes one from `D:P` to the relay,
+    /// `P` being the nat port of the connection, and the answers of the relay
+    /// to `D:P` go back as from `D:dp` to `C:cp`. Returns whether the packet
+    /// is to be written back to the tun.
+    fn handle_tcp(&self, packet: &mut MutableIpv4Packet<'_>, relay_port: u16) -> bool {
+        let src = packet.get_source();
+        let dst = packet.get_destination();
+        let (src_port, dst_port, flags) = match TcpPacket::new(packet.payload()) {
+            Some(v) => (v.get_source(), v.get_destination(), v.get_flags()),
+            None => return false,
+        };
+
+        let gateway = self.net.addr();
+        let now = Instant::now();
+        let mut nat = self.nat.lock().unwrap();
+        let (port, src, dst) = if src == gateway && src_port == relay_port {
+            match nat.get(Protocol::Tcp, dst_port, now) {
+                Some(v) if *v.dst.ip() == dst => (dst_port, v.dst, v.src),
+                _ => return false,
+            }
+        } else if self.is_relayed(&dst) {
+            let port = nat.allocate(
+                Protocol::Tcp,
+                SocketAddrV4::new(src, src_port),
+                SocketAddrV4::new(dst, dst_port),
+                now,
+            );
+            (
+                port,
+                SocketAddrV4::new(dst, port),
+                SocketAddrV4::new(gateway, relay_port),
+            )
+        } else {
+            return false;
+        };
+        if flags & (TcpFlags::FIN | TcpFlags::RST) != 0 {
+            nat.close(Protocol::Tcp, port);
+        } else if flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN {
+            // the addresses of a closing connection reused by a new one
+            nat.open(Protocol::Tcp, port);
+        }
+        drop(nat);
+
+        {
+            let mut segment = match MutableTcpPacket::new(packet.payload_mut()) {
+                Some(v) => v,
+                None => return false,
+            };
+            segment.set_source(src.port());
+            segment.set_destination(dst.port());
+            let checksum = tcp::ipv4_checksum(&segment.to_immutable(), src.ip(), dst.ip());
+            segment.set_checksum(checksum);
+        }
+        packet.set_source(*src.ip());
+        packet.set_destination(*dst.ip());
+        packet.set_checksum(ipv4::checksum(&packet.to_immutable()));
+        true
+    }
+
+    fn is_relayed(&self, ip: &Ipv4Addr) -> bool {
+        *ip != self.net.addr()
+            && (self.pool.lock().unwrap().contains(ip)
+                || self.routes.iter().any(|(net, _)| net.contains(ip)))
+    }
+
+    async fn accept(&self, mut listener: TcpListener) {
+        loop {
+            let (conn, peer) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    debug!("accept tcp relay ({}) connection error: {}", self.id, e);
+                    continue;
+                }
+            };
+            match self.redirect(peer) {
+                Ok((proxy, target)) => {
+                    tokio::spawn(relay(conn, proxy, target));
+                }
+                Err(e) => debug!("drop tcp connection from {}, {}", peer, e),
+            }
+        }
+    }
+
+    /// The proxy and the target of a redirected connection, as accepted from
+    /// `peer`.
+    fn redirect(&self, peer: SocketAddr) -> Result<(Endpoint, Address), String> {
+        let session = match peer {
+            SocketAddr::V4(v) => {
+                let mut nat = self.nat.lock().unwrap();
+                nat.get(Protocol::Tcp, v.port(), Instant::now())
+            }
+            SocketAddr::V6(_) => None,
+        };
+        let dst = match session {
+            Some(v) if SocketAddr::V4(v.dst).ip() == peer.ip() => v.dst,
+            _ => return Err("no nat session".to_string()),
+        };
+        self.target(dst)
+    }
+
+    /// The proxy and the target of traffic to `dst`, a fake ip is connected
+    /// to by its leased domain.
+    fn target(&self, dst: SocketAddrV4) -> Result<(Endpoint, Address), String> {
+        let lease = self
+            .pool
+            .lock()
+            .unwrap()
+            .lookup(dst.ip())
+            .map(|v| (v.target.clone(), v.domain.clone()));
+        let (target, address) = match lease {
+            Some((target, domain)) => (target, Address::Domain(domain, dst.port())),
+            None => match self.routes.iter().find(|(net, _)| net.contains(dst.ip())) {
+                Some((_, target)) => (target.clone(), Address::Ip(SocketAddr::V4(dst))),
+                None => return Err(format!("no rule of {}", dst)),
+            },
+        };
+
+        let proxy = self
+            .proxies
+            .select(&target, &address)
+            .ok_or_else(|| format!("no proxy {}", target))?;
+        Ok((proxy, address))
+    }
+
     async fn handle_udp(
         &self,
         packet: &mut MutableIpv4Packet<'_>,
         mut payload: Vec<u8>,
         stream: &mut Framed<AsyncDevice, TunPacketCodec>,
+        tun: &UnboundedSender<Vec<u8>>,
     ) {
         let udp_pkt = MutableUdpPacket::new(&mut payload).unwrap();
         let s_port = udp_pkt.get_source();
@@ -216,7 +487,7 @@ impl Gateway {
             let head_len = (packet.get_header_length() * 4) as usize;
             let mut pkt_data = vec![0u8; head_len + icmp_data.len()];
             pkt_data[0..head_len].copy_from_slice(&payload[0..head_len]);
-            let total_len = (&pkt_data).len() as u16;
+            let total_len = pkt_data.len() as u16;
             let mut pkt = MutableIpv4Packet::new(&mut pkt_data).unwrap();
             pkt.set_ttl(64);
             pkt.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
@@ -227,6 +498,618 @@ impl Gateway {
             pkt.set_checksum(ipv4::checksum(&pkt.to_immutable()));
 
             let _ = stream.send(TunPacket::new(packet.packet().to_vec())).await;
+            return;
+        }
+
+        self.relay_udp(
+            SocketAddrV4::new(src, s_port),
+            SocketAddrV4::new(dst, d_port),
+            udp_pkt.payload(),
+            tun,
+        );
+    }
+
+    /// Sends a datagram of fake ips and routed networks to the flow of its
+    /// addresses, starting a relay through the proxy for a new flow.
+    fn relay_udp(
+        &self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        data: &[u8],
+        tun: &UnboundedSender<Vec<u8>>,
+    ) {
+        if !self.is_relayed(dst.ip()) {
+            return;
+        }
+        self.nat
+            .lock()
+            .unwrap()
+            .allocate(Protocol::Udp, src, dst, Instant::now());
+
+        let mut flows = self.flows.lock().unwrap();
+        if let Some(flow) = flows.get(&(src, dst)) {
+            // fails if the flow is ending
+            if flow.sender.send(data.to_vec()).is_ok() {
+                return;
+            }
+        }
+
+        let (proxy, target) = match self.target(dst) {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("drop udp from {} to {}, {}", src, dst, e);
+                return;
+            }
+        };
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let _ = sender.send(data.to_vec());
+        let id = NEXT_FLOW.fetch_add(1, Ordering::Relaxed);
+        flows.insert((src, dst), UdpFlow { id, sender });
+
+        let flows = self.flows.clone();
+        let nat = self.nat.clone();
+        let tun = tun.clone();
+        tokio::spawn(async move {
+            relay_datagrams(&proxy, &target, src, dst, receiver, nat, tun).await;
+            let mut flows = flows.lock().unwrap();
+            if flows.get(&(src, dst)).map_or(false, |v| v.id == id) {
+                flows.remove(&(src, dst));
+            }
+        });
+    }
+}
+
+/// Relays the datagrams of the flow from `src` to `dst` through a udp
+/// association with the socks5 `proxy`, writing the answers to the tun.
+/// Answers keep the nat session alive as the datagrams do. Ends once the
+/// flow is idle or the proxy closes the association.
+async fn relay_datagrams(
+    proxy: &Endpoint,
+    target: &Address,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    mut datagrams: UnboundedReceiver<Vec<u8>>,
+    nat: Arc<Mutex<Nat>>,
+    tun: UnboundedSender<Vec<u8>>,
+) {
+    metrics::UDP_FLOWS.inc();
+    let (mut control, relay) = match socks5::associate(proxy).await {
+        Ok(v) => v,
+        Err(e) => {
+            metrics::UDP_PROXY_ERRORS.inc();
+            warn!("associate {:?} via {}, err: {}", target, proxy, e);
+            return;
+        }
+    };
+    let socket = async {
+        let bind = match relay {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(relay).await?;
+        Ok::<_, io::Error>(socket)
+    };
+    let (mut receiver, mut sender) = match socket.await {
+        Ok(v) => v.split(),
+        Err(e) => {
+            metrics::UDP_PROXY_ERRORS.inc();
+            warn!("connect udp relay {} of {}, err: {}", relay, proxy, e);
+            return;
+        }
+    };
+    debug!("relay udp to {:?} via {}", target, proxy);
+
+    let mut buf = vec![0u8; 65536];
+    let mut closed = [0u8; 1];
+    loop {
+        tokio::select! {
+            data = datagrams.recv() => {
+                let data = match data {
+                    Some(v) => v,
+                    None => break,
+                };
+                let result = match socks5::encode_udp(target, &data) {
+                    Ok(v) => sender.send(&v).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    debug!("send udp to {:?} via {}, err: {}", target, proxy, e);
+                }
+            }
+            answer = receiver.recv(&mut buf) => {
+                let answer = answer.and_then(|n| {
+                    socks5::decode_udp(&buf[..n]).map(|(_, data)| udp_packet(dst, src, data))
+                });
+                match answer {
+                    Ok(Some(v)) => {
+                        let mut nat = nat.lock().unwrap();
+                        nat.allocate(Protocol::Udp, src, dst, Instant::now());
+                        let _ = tun.send(v);
+                    }
+                    Ok(None) => debug!("drop udp of {:?} via {}, too large", target, proxy),
+                    Err(e) => debug!("receive udp of {:?} via {}, err: {}", target, proxy, e),
+                }
+            }
+            _ = control.read(&mut closed) => break,
+            _ = tokio::time::delay_for(nat::UDP_TIMEOUT) => break,
+        }
+    }
+}
+
+/// Builds the ip packet of a datagram from `src` to `dst`, none if the
+/// payload does not fit in one.
+fn udp_packet(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Option<Vec<u8>> {
+    let len = 28 + payload.len();
+    if len > u16::MAX as usize {
+        return None;
+    }
+    let mut data = vec![0u8; len];
+    let (ip_data, udp_data) = data.split_at_mut(20);
+
+    let mut datagram = MutableUdpPacket::new(udp_data).unwrap();
+    datagram.set_source(src.port());
+    datagram.set_destination(dst.port());
+    datagram.set_length(len as u16 - 20);
+    datagram.set_payload(payload);
+    let checksum = udp::ipv4_checksum(&datagram.to_immutable(), src.ip(), dst.ip());
+    datagram.set_checksum(checksum);
+
+    let mut ip = MutableIpv4Packet::new(ip_data).unwrap();
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length(len as u16);
+    ip.set_ttl(64);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+    ip.set_source(*src.ip());
+    ip.set_destination(*dst.ip());
+    ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+
+    Some(data)
+}
+
+/// Relays `conn` to `target` through the socks5 `proxy` until both sides
+/// are closed.
+async fn relay(mut conn: TcpStream, proxy: Endpoint, target: Address) {
+    metrics::TCP_CONNECTIONS.inc();
+    let mut upstream = match socks5::connect(&proxy, &target).await {
+        Ok(v) => v,
+        Err(e) => {
+            metrics::TCP_PROXY_ERRORS.inc();
+            warn!("connect {:?} via {}, err: {}", target, proxy, e);
+            return;
+        }
+    };
+    debug!("relay tcp to {:?} via {}", target, proxy);
+
+    let (mut conn_reader, mut conn_writer) = conn.split();
+    let (mut upstream_reader, mut upstream_writer) = upstream.split();
+    let outbound = async {
+        let n = io::copy(&mut conn_reader, &mut upstream_writer).await?;
+        upstream_writer.shutdown().await?;
+        Ok::<_, io::Error>(n)
+    };
+    let inbound = async {
+        let n = io::copy(&mut upstream_reader, &mut conn_writer).await?;
+        conn_writer.shutdown().await?;
+        Ok::<_, io::Error>(n)
+    };
+    if let Err(e) = try_join(outbound, inbound).await {
+        debug!("relay tcp to {:?}, err: {}", target, e);
+    }
+}
+
+/// Builds the RST answering a tcp segment, so the client fails fast instead
+/// of retransmitting. Returns `None` for other packets and RSTs.
+fn tcp_reset(packet: &Ipv4Packet) -> Option<Vec<u8>> {
+    if packet.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
+        return None;
+    }
+    let segment = TcpPacket::new(packet.payload())?;
+    let flags = segment.get_flags();
+    if flags & TcpFlags::RST != 0 {
+        return None;
+    }
+
+    let mut data = vec![0u8; 40];
+    let (ip_data, tcp_data) = data.split_at_mut(20);
+
+    let mut reset = tcp::MutableTcpPacket::new(tcp_data)?;
+    reset.set_source(segment.get_destination());
+    reset.set_destination(segment.get_source());
+    reset.set_data_offset(5);
+    if flags & TcpFlags::ACK != 0 {
+        reset.set_sequence(segment.get_acknowledgement());
+        reset.set_flags(TcpFlags::RST);
+    } else {
+        let mut len = segment.payload().len() as u32;
+        if flags & TcpFlags::SYN != 0 {
+            len += 1;
+        }
+        if flags & TcpFlags::FIN != 0 {
+            len += 1;
+        }
+        reset.set_acknowledgement(segment.get_sequence().wrapping_add(len));
+        reset.set_flags(TcpFlags::RST | TcpFlags::ACK);
+    }
+    let checksum = tcp::ipv4_checksum(
+        &reset.to_immutable(),
+        &packet.get_destination(),
+        &packet.get_source(),
+    );
+    reset.set_checksum(checksum);
+
+    let mut ip = MutableIpv4Packet::new(ip_data)?;
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length(40);
+    ip.set_ttl(64);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    ip.set_source(packet.get_destination());
+    ip.set_destination(packet.get_source());
+    ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+
+    Some(data)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use pnet::packet::{
+        ip::IpNextHeaderProtocols,
+        ipv4::{self, Ipv4Packet, MutableIpv4Packet},
+        tcp::{self, MutableTcpPacket, TcpFlags, TcpPacket},
+        udp::{self, UdpPacket},
+        Packet,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        runtime::Runtime,
+        sync::mpsc,
+    };
+
+    use super::{udp_packet, Gateway};
+    use crate::{
+        metrics,
+        nat::{self, Nat, Protocol, State},
+        pool::IpPool,
+        proxy::Proxies,
+        setting::Setting,
+        socks5::{self, test::endpoint},
+    };
+
+    const TEST_CONFIG: &str = r#"
+dns_upstream: [127.0.0.1]
+dns_fallback: [127.0.0.1]
+network:
+  - 10.85.0.1/16
+proxy:
+  - name: v2ray_hk
+    values:
+      - socks5://PROXY?weight=1
+rules:
+  - type: route
+    target: v2ray_hk
+    values:
+      - 93.184.216.0/24
+  - type: route
+    target: reject
+    values:
+      - 198.51.100.0/24
+"#;
+
+    fn test_gateway(proxy: &str) -> Gateway {
+        let setting = Setting::from_yaml(&TEST_CONFIG.replace("PROXY", proxy)).unwrap();
+        let pool = Arc::new(Mutex::new(IpPool::load(&setting).unwrap()));
+        let nat = Arc::new(Mutex::new(Nat::new(1024)));
+        let proxies = Arc::new(Proxies::new(&setting.proxy, 2, 3));
+        Gateway::new(0, "10.85.0.1/16", setting, pool, nat, proxies)
+    }
+
+    fn tcp_packet(flags: u16, payload: &[u8]) -> Vec<u8> {
+        tcp_segment(
+            "10.85.0.1:50000".parse().unwrap(),
+            "93.184.216.34:443".parse().unwrap(),
+            flags,
+            payload,
+        )
+    }
+
+    fn tcp_segment(src: SocketAddrV4, dst: SocketAddrV4, flags: u16, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; 40 + payload.len()];
+        {
+            let mut segment = MutableTcpPacket::new(&mut data[20..]).unwrap();
+            segment.set_source(src.port());
+            segment.set_destination(dst.port());
+            segment.set_sequence(1000);
+            segment.set_acknowledgement(2000);
+            segment.set_data_offset(5);
+            segment.set_flags(flags);
+            segment.set_payload(payload);
         }
+        let total = data.len() as u16;
+        let mut packet = MutableIpv4Packet::new(&mut data).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length(total);
+        packet.set_ttl(64);
+        packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        packet.set_source(*src.ip());
+        packet.set_destination(*dst.ip());
+        data
+    }
+
+    /// Addresses of a valid tcp packet.
+    fn addrs(data: &[u8]) -> (SocketAddrV4, SocketAddrV4) {
+        let packet = Ipv4Packet::new(data).unwrap();
+        assert_eq!(packet.get_checksum(), ipv4::checksum(&packet));
+        let segment = TcpPacket::new(packet.payload()).unwrap();
+        let (src, dst) = (packet.get_source(), packet.get_destination());
+        assert_eq!(
+            segment.get_checksum(),
+            tcp::ipv4_checksum(&segment, &src, &dst)
+        );
+        (
+            SocketAddrV4::new(src, segment.get_source()),
+            SocketAddrV4::new(dst, segment.get_destination()),
+        )
+    }
+
+    fn handle_tcp(gateway: &Gateway, data: &mut [u8]) -> bool {
+        let mut packet = MutableIpv4Packet::new(data).unwrap();
+        gateway.handle_tcp(&mut packet, 8000)
+    }
+
+    #[test]
+    fn test_tcp_reset() {
+        let syn = tcp_packet(TcpFlags::SYN, &[]);
+        let reset = super::tcp_reset(&Ipv4Packet::new(&syn).unwrap()).unwrap();
+        let packet = Ipv4Packet::new(&reset).unwrap();
+        assert_eq!(packet.get_source(), Ipv4Addr::new(93, 184, 216, 34));
+        assert_eq!(packet.get_destination(), Ipv4Addr::new(10, 85, 0, 1));
+        assert_eq!(packet.get_checksum(), ipv4::checksum(&packet));
+        let segment = TcpPacket::new(packet.payload()).unwrap();
+        assert_eq!(segment.get_source(), 443);
+        assert_eq!(segment.get_destination(), 50000);
+        assert_eq!(segment.get_flags(), TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!(segment.get_acknowledgement(), 1001);
+        assert_eq!(
+            segment.get_checksum(),
+            tcp::ipv4_checksum(&segment, &packet.get_source(), &packet.get_destination())
+        );
+
+        let data = tcp_packet(TcpFlags::ACK | TcpFlags::PSH, b"hello");
+        let reset = super::tcp_reset(&Ipv4Packet::new(&data).unwrap()).unwrap();
+        let packet = Ipv4Packet::new(&reset).unwrap();
+        let segment = TcpPacket::new(packet.payload()).unwrap();
+        assert_eq!(segment.get_flags(), TcpFlags::RST);
+        assert_eq!(segment.get_sequence(), 2000);
+
+        let rst = tcp_packet(TcpFlags::RST, &[]);
+        assert!(super::tcp_reset(&Ipv4Packet::new(&rst).unwrap()).is_none());
+        let mut udp = tcp_packet(0, &[]);
+        MutableIpv4Packet::new(&mut udp)
+            .unwrap()
+            .set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        assert!(super::tcp_reset(&Ipv4Packet::new(&udp).unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_handle_tcp() {
+        let gateway = test_gateway("127.0.0.1:1082");
+        let relay: SocketAddrV4 = "10.85.0.1:8000".parse().unwrap();
+        let client: SocketAddrV4 = "10.85.0.1:50000".parse().unwrap();
+        let server: SocketAddrV4 = "93.184.216.34:443".parse().unwrap();
+
+        let mut syn = tcp_packet(TcpFlags::SYN, &[]);
+        assert!(handle_tcp(&gateway, &mut syn));
+        let (src, dst) = addrs(&syn);
+        assert_eq!(*src.ip(), *server.ip());
+        assert_eq!(dst, relay);
+        let mut data = tcp_packet(TcpFlags::ACK, b"hello");
+        assert!(handle_tcp(&gateway, &mut data));
+        assert_eq!(addrs(&data), (src, dst));
+        assert_eq!(&data[40..], b"hello");
+
+        let mut reply = tcp_segment(relay, src, TcpFlags::SYN | TcpFlags::ACK, &[]);
+        assert!(handle_tcp(&gateway, &mut reply));
+        assert_eq!(addrs(&reply), (server, client));
+
+        // a FIN closes the session
+        let now = std::time::Instant::now();
+        let session = gateway
+            .nat
+            .lock()
+            .unwrap()
+            .get(Protocol::Tcp, src.port(), now);
+        assert_eq!(session.unwrap().state, State::Established);
+        let mut fin = tcp_segment(relay, src, TcpFlags::FIN | TcpFlags::ACK, &[]);
+        assert!(handle_tcp(&gateway, &mut fin));
+        let session = gateway
+            .nat
+            .lock()
+            .unwrap()
+            .get(Protocol::Tcp, src.port(), now);
+        assert_eq!(session.unwrap().state, State::Closing);
+
+        // a new connection from the same port opens it again
+        let mut syn = tcp_packet(TcpFlags::SYN, &[]);
+        assert!(handle_tcp(&gateway, &mut syn));
+        assert_eq!(addrs(&syn), (src, dst));
+        let session = gateway
+            .nat
+            .lock()
+            .unwrap()
+            .get(Protocol::Tcp, src.port(), now);
+        assert_eq!(session.unwrap().state, State::Established);
+
+        // unknown sessions and other destinations pass untouched
+        let other = SocketAddrV4::new(*server.ip(), src.port() + 1);
+        let mut reply = tcp_segment(relay, other, TcpFlags::ACK, &[]);
+        assert!(!handle_tcp(&gateway, &mut reply));
+        let mut data = tcp_segment(client, "8.8.8.8:443".parse().unwrap(), TcpFlags::SYN, &[]);
+        assert!(!handle_tcp(&gateway, &mut data));
+        let mut data = tcp_segment(client, "10.85.0.1:80".parse().unwrap(), TcpFlags::SYN, &[]);
+        assert!(!handle_tcp(&gateway, &mut data));
+    }
+
+    #[test]
+    fn test_redirect() {
+        let gateway = test_gateway("127.0.0.1:1082");
+        let client: SocketAddrV4 = "10.85.0.1:50000".parse().unwrap();
+
+        let mut data = tcp_segment(client, "93.184.216.34:443".parse().unwrap(), 0, &[]);
+        handle_tcp(&gateway, &mut data);
+        let (peer, _) = addrs(&data);
+        let (proxy, target) = gateway.redirect(peer.into()).unwrap();
+        assert_eq!(proxy.addr(), "127.0.0.1:1082");
+        assert_eq!(
+            target,
+            socks5::Address::Ip("93.184.216.34:443".parse().unwrap())
+        );
+
+        // a fake ip connects to its domain
+        let ip = gateway
+            .pool
+            .lock()
+            .unwrap()
+            .allocate("www.google.com.", "v2ray_hk");
+        let mut data = tcp_segment(client, SocketAddrV4::new(ip, 80), 0, &[]);
+        handle_tcp(&gateway, &mut data);
+        let (peer, _) = addrs(&data);
+        let (_, target) = gateway.redirect(peer.into()).unwrap();
+        assert_eq!(
+            target,
+            socks5::Address::Domain("www.google.com".to_string(), 80)
+        );
+
+        let ip = gateway
+            .pool
+            .lock()
+            .unwrap()
+            .allocate("www.qq.com", "v2ray_jp");
+        let mut data = tcp_segment(client, SocketAddrV4::new(ip, 80), 0, &[]);
+        handle_tcp(&gateway, &mut data);
+        let (peer, _) = addrs(&data);
+        assert_eq!(
+            gateway.redirect(peer.into()).unwrap_err(),
+            "no proxy v2ray_jp"
+        );
+        let peer = SocketAddrV4::new(Ipv4Addr::new(93, 184, 216, 35), peer.port());
+        assert!(gateway.redirect(peer.into()).is_err());
+    }
+
+    #[test]
+    fn test_relay() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (proxy, mut targets) = socks5::test::stub_server().await;
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+
+            let connections = metrics::TCP_CONNECTIONS.get();
+            let target = socks5::Address::Domain("www.google.com".to_string(), 443);
+            tokio::spawn(super::relay(conn, endpoint(proxy), target.clone()));
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            assert_eq!(targets.recv().await.unwrap(), target);
+            assert!(metrics::TCP_CONNECTIONS.get() > connections);
+
+            // closed once the client is done
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+
+            // a failed connect closes the connection
+            let errors = metrics::TCP_PROXY_ERRORS.get();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+            let target = socks5::Address::Ip("192.0.2.1:1".parse().unwrap());
+            tokio::spawn(super::relay(conn, endpoint(proxy), target));
+            assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+            assert!(metrics::TCP_PROXY_ERRORS.get() > errors);
+        });
+    }
+
+    #[test]
+    fn test_relay_udp() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (proxy, mut targets) = socks5::test::stub_server().await;
+            let gateway = test_gateway(&proxy.to_string());
+            let (tun, mut replies) = mpsc::unbounded_channel();
+            let client: SocketAddrV4 = "10.85.0.1:50000".parse().unwrap();
+            let server: SocketAddrV4 = "93.184.216.34:53".parse().unwrap();
+
+            let flows = metrics::UDP_FLOWS.get();
+            gateway.relay_udp(client, server, b"query", &tun);
+            gateway.relay_udp(client, server, b"again", &tun);
+            for data in [&b"query"[..], &b"again"[..]].iter() {
+                let reply = replies.recv().await.unwrap();
+                let packet = Ipv4Packet::new(&reply).unwrap();
+                assert_eq!(packet.get_checksum(), ipv4::checksum(&packet));
+                assert_eq!(packet.get_source(), *server.ip());
+                assert_eq!(packet.get_destination(), *client.ip());
+                let datagram = UdpPacket::new(packet.payload()).unwrap();
+                assert_eq!(
+                    datagram.get_checksum(),
+                    udp::ipv4_checksum(&datagram, server.ip(), client.ip())
+                );
+                assert_eq!(datagram.get_source(), server.port());
+                assert_eq!(datagram.get_destination(), client.port());
+                assert_eq!(datagram.payload(), *data);
+                assert_eq!(
+                    targets.recv().await.unwrap(),
+                    socks5::Address::Ip(server.into())
+                );
+            }
+            // one association for the flow
+            assert_eq!(gateway.flows.lock().unwrap().len(), 1);
+            assert!(metrics::UDP_FLOWS.get() > flows);
+
+            // a fake ip is relayed to its domain, other addresses are not
+            let ip = gateway
+                .pool
+                .lock()
+                .unwrap()
+                .allocate("dns.google", "v2ray_hk");
+            gateway.relay_udp(client, SocketAddrV4::new(ip, 53), b"query", &tun);
+            replies.recv().await.unwrap();
+            assert_eq!(
+                targets.recv().await.unwrap(),
+                socks5::Address::Domain("dns.google".to_string(), 53)
+            );
+            gateway.relay_udp(client, "8.8.8.8:53".parse().unwrap(), b"query", &tun);
+            assert_eq!(gateway.flows.lock().unwrap().len(), 2);
+
+            // the flow keeps its association once the nat session is gone
+            gateway
+                .nat
+                .lock()
+                .unwrap()
+                .expire(Instant::now() + nat::UDP_TIMEOUT);
+            assert_eq!(gateway.nat.lock().unwrap().stats().udp, 0);
+            gateway.relay_udp(client, server, b"later", &tun);
+            replies.recv().await.unwrap();
+            targets.recv().await.unwrap();
+            assert_eq!(gateway.flows.lock().unwrap().len(), 2);
+            assert_eq!(gateway.nat.lock().unwrap().stats().udp, 1);
+            assert_eq!(metrics::UDP_FLOWS.get(), flows + 2);
+
+            assert!(udp_packet(server, client, &vec![0; 65535 - 28]).is_some());
+            assert!(udp_packet(server, client, &vec![0; 65535 - 27]).is_none());
+        });
     }
 }
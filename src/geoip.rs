@@ -0,0 +1,204 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use maxminddb::{geoip2, Reader};
+
+use crate::setting::{Rule, RuleType};
+
+/// Compiled `dnsCidrArea` rules, backed by a GeoLite2 country database.
+pub struct GeoIp {
+    reader: Option<Reader<Vec<u8>>>,
+    areas: Vec<(String, usize)>,
+}
+
+impl GeoIp {
+    pub fn new(path: &str, rules: &[Rule]) -> Result<Self, String> {
+        let areas = areas(rules);
+        if areas.is_empty() {
+            return Ok(GeoIp {
+                reader: None,
+                areas,
+            });
+        }
+
+        if path.is_empty() {
+            return Err("dnsCidrArea rule requires geoip database".to_string());
+        }
+
+        let reader = Reader::open_readfile(path)
+            .map_err(|e| format!("load geoip database: {}, err: {:?}", path, e))?;
+        debug!("geoip database loaded: {}", path);
+        Ok(GeoIp {
+            reader: Some(reader),
+            areas,
+        })
+    }
+
+    #[cfg(test)]
+    fn with_reader(reader: Reader<Vec<u8>>, rules: &[Rule]) -> Self {
+        GeoIp {
+            reader: Some(reader),
+            areas: areas(rules),
+        }
+    }
+
+    /// Returns the index of the first rule whose areas contain any of `ips`.
+    pub fn find(&self, ips: &[Ipv4Addr]) -> Option<usize> {
+        let reader = self.reader.as_ref()?;
+        let countries: Vec<String> = ips
+            .iter()
+            .filter_map(|ip| {
+                let country: geoip2::Country = reader.lookup(IpAddr::V4(*ip)).ok()?;
+                country.country?.iso_code.map(|v| v.to_lowercase())
+            })
+            .collect();
+
+        self.areas
+            .iter()
+            .find(|(area, _)| countries.contains(area))
+            .map(|(_, i)| *i)
+    }
+}
+
+fn areas(rules: &[Rule]) -> Vec<(String, usize)> {
+    let mut areas = vec![];
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.rule_type != RuleType::DnsCidrArea {
+            continue;
+        }
+        for value in &rule.values {
+            areas.push((value.trim().to_lowercase(), i));
+        }
+    }
+    areas
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn string(v: &str) -> Vec<u8> {
+        let mut buf = vec![(2 << 5) | v.len() as u8];
+        buf.extend_from_slice(v.as_bytes());
+        buf
+    }
+
+    fn map(pairs: Vec<(&str, Vec<u8>)>) -> Vec<u8> {
+        let mut buf = vec![(7 << 5) | pairs.len() as u8];
+        for (k, v) in pairs {
+            buf.extend(string(k));
+            buf.extend(v);
+        }
+        buf
+    }
+
+    fn uint(kind: u8, v: u64, size: usize) -> Vec<u8> {
+        let bytes = v.to_be_bytes();
+        let mut buf = if kind < 8 {
+            vec![(kind << 5) | size as u8]
+        } else {
+            vec![size as u8, kind - 7]
+        };
+        buf.extend_from_slice(&bytes[8 - size..]);
+        buf
+    }
+
+    /// Builds an ipv4 mmdb with 24 bit records, mapping each /8 prefix to
+    /// a country iso code.
+    fn mmdb(countries: &[(u8, &str)]) -> Vec<u8> {
+        let mut data = vec![];
+        let mut nodes: Vec<[Option<u32>; 2]> = vec![[None, None]];
+        let mut leaves = vec![];
+        for (prefix, iso_code) in countries {
+            let offset = data.len() as u32;
+            data.extend(map(vec![(
+                "country",
+                map(vec![("iso_code", string(iso_code))]),
+            )]));
+
+            let mut node = 0;
+            for i in 0..8 {
+                let bit = ((prefix >> (7 - i)) & 1) as usize;
+                if i == 7 {
+                    leaves.push((node, bit, offset));
+                    break;
+                }
+                node = match nodes[node][bit] {
+                    Some(v) => v as usize,
+                    None => {
+                        nodes.push([None, None]);
+                        nodes[node][bit] = Some(nodes.len() as u32 - 1);
+                        nodes.len() - 1
+                    }
+                };
+            }
+        }
+
+        let node_count = nodes.len() as u32;
+        let mut records: Vec<[u32; 2]> = nodes
+            .iter()
+            .map(|v| [v[0].unwrap_or(node_count), v[1].unwrap_or(node_count)])
+            .collect();
+        for (node, bit, offset) in leaves {
+            records[node][bit] = node_count + 16 + offset;
+        }
+
+        let mut buf = vec![];
+        for record in records {
+            for v in record.iter() {
+                buf.extend_from_slice(&v.to_be_bytes()[1..]);
+            }
+        }
+        buf.extend_from_slice(&[0u8; 16]);
+        buf.extend(data);
+        buf.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        buf.extend(map(vec![
+            ("binary_format_major_version", uint(5, 2, 1)),
+            ("binary_format_minor_version", uint(5, 0, 0)),
+            ("build_epoch", uint(9, 0, 0)),
+            ("database_type", string("Test-Country")),
+            ("description", map(vec![])),
+            ("ip_version", uint(5, 4, 1)),
+            ("languages", vec![0, 4]),
+            ("node_count", uint(6, node_count as u64, 4)),
+            ("record_size", uint(5, 24, 1)),
+        ]));
+        buf
+    }
+
+    fn rule(rule_type: RuleType, target: &str, values: &[&str]) -> Rule {
+        Rule {
+            rule_type,
+            target: target.to_string(),
+            values: values.iter().map(|v| v.to_string()).collect(),
+            upstream: None,
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let reader = Reader::from_source(mmdb(&[(1, "HK"), (2, "US"), (3, "CN")])).unwrap();
+        let rules = vec![
+            rule(RuleType::DnsCidrArea, "hk", &["hk"]),
+            rule(RuleType::Domain, "hk", &["*.google.com"]),
+            rule(RuleType::DnsCidrArea, "us", &["US", "jp"]),
+        ];
+        let geoip = GeoIp::with_reader(reader, &rules);
+
+        let ips = |v: &[&str]| -> Vec<Ipv4Addr> { v.iter().map(|v| v.parse().unwrap()).collect() };
+        assert_eq!(geoip.find(&ips(&["1.2.3.4"])), Some(0));
+        assert_eq!(geoip.find(&ips(&["2.2.3.4"])), Some(2));
+        assert_eq!(geoip.find(&ips(&["2.2.3.4", "1.0.0.1"])), Some(0));
+        assert_eq!(geoip.find(&ips(&["3.3.3.3", "4.4.4.4"])), None);
+        assert_eq!(geoip.find(&[]), None);
+    }
+
+    #[test]
+    fn test_new() {
+        let rules = vec![rule(RuleType::DnsCidrArea, "hk", &["hk"])];
+        assert!(GeoIp::new("", &rules).is_err());
+        assert!(GeoIp::new("/nonexistent/GeoLite2-Country.mmdb", &rules).is_err());
+
+        let geoip = GeoIp::new("", &[]).unwrap();
+        assert_eq!(geoip.find(&["1.2.3.4".parse().unwrap()]), None);
+    }
+}
@@ -0,0 +1,159 @@
+use std::{collections::HashMap, net::IpAddr, str::FromStr};
+
+use trust_dns_client::rr::Name;
+
+/// Parsed `hosts` setting.
+///
+/// Each line is `target hostname [hostname ...]`, `target` is either an ip
+/// or another hostname the names are aliased to (answered as a CNAME).
+/// `#` starts a comment, either a whole line or the rest of a line.
+#[derive(Debug, Default)]
+pub struct Hosts {
+    entries: HashMap<String, Target>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Ips(Vec<IpAddr>),
+    Alias(Name),
+}
+
+impl Hosts {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut hosts = Hosts::default();
+        for (i, line) in text.lines().enumerate() {
+            hosts
+                .parse_line(line)
+                .map_err(|e| format!("hosts line {}: {}", i + 1, e))?;
+        }
+        Ok(hosts)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Target> {
+        self.entries.get(&normalize(name))
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        };
+        let mut fields = line.split_whitespace();
+        let target = match fields.next() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let names: Vec<&str> = fields.collect();
+        if names.is_empty() {
+            return Err(format!("missing hostname for {}", target));
+        }
+
+        let target = match target.parse::<IpAddr>() {
+            Ok(ip) => Target::Ips(vec![ip]),
+            Err(_) => {
+                if !is_hostname(target) {
+                    return Err(format!("invalid target: {}", target));
+                }
+                let name = Name::from_str(target)
+                    .map_err(|e| format!("invalid target: {}, err: {}", target, e))?;
+                Target::Alias(name.append_domain(&Name::root()))
+            }
+        };
+
+        for name in names {
+            if !is_hostname(name) {
+                return Err(format!("invalid hostname: {}", name));
+            }
+            let name = normalize(name);
+            match (self.entries.get_mut(&name), &target) {
+                (None, _) => {
+                    self.entries.insert(name, target.clone());
+                }
+                (Some(Target::Ips(ips)), Target::Ips(v)) => ips.extend(v),
+                _ => return Err(format!("conflicting entry for hostname: {}", name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_hostname(v: &str) -> bool {
+    let v = v.strip_suffix('.').unwrap_or(v);
+    // an all numeric top label is more likely a malformed ip
+    let top = v.rsplit('.').next().unwrap_or_default();
+    !v.is_empty()
+        && !top.chars().all(|c| c.is_ascii_digit())
+        && v.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let hosts = Hosts::parse(
+            r#"
+# 支持别名, `#` 为注释，支持行/行尾
+# target  hostname
+192.168.1.20                  myapp.com      # 我的app
+192.168.1.21                  MyApp.com  www.myapp.com
+::1                           ip6.myapp.com
+cdn.myapp.com.a.bdydns.com.   cdn.myapp.com  # 我的app CDN
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            hosts.get("myapp.com."),
+            Some(&Target::Ips(vec![
+                "192.168.1.20".parse().unwrap(),
+                "192.168.1.21".parse().unwrap()
+            ]))
+        );
+        assert_eq!(
+            hosts.get("www.myapp.com"),
+            Some(&Target::Ips(vec!["192.168.1.21".parse().unwrap()]))
+        );
+        assert_eq!(
+            hosts.get("ip6.myapp.com"),
+            Some(&Target::Ips(vec!["::1".parse().unwrap()]))
+        );
+        assert_eq!(
+            hosts.get("CDN.myapp.com"),
+            Some(&Target::Alias(
+                Name::from_str("cdn.myapp.com.a.bdydns.com.").unwrap()
+            ))
+        );
+        assert_eq!(hosts.get("a.myapp.com"), None);
+        assert!(Hosts::parse("").unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let err = Hosts::parse("# comment\n192.168.1.20\n").unwrap_err();
+        assert_eq!(err, "hosts line 2: missing hostname for 192.168.1.20");
+
+        let err = Hosts::parse("192.168.1.20 my*app.com").unwrap_err();
+        assert_eq!(err, "hosts line 1: invalid hostname: my*app.com");
+
+        let err = Hosts::parse("\n\n192.168.1.300 myapp.com").unwrap_err();
+        assert_eq!(err, "hosts line 3: invalid target: 192.168.1.300");
+
+        let err = Hosts::parse("192.168.1.20 myapp.com\ncdn.com myapp.com").unwrap_err();
+        assert_eq!(
+            err,
+            "hosts line 2: conflicting entry for hostname: myapp.com"
+        );
+    }
+}
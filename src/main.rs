@@ -3,12 +3,24 @@ extern crate serde_derive;
 #[macro_use]
 extern crate log;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+mod cache;
 mod dns;
 mod gateway;
+mod geoip;
+mod hosts;
 mod logger;
+mod metrics;
+mod nat;
+mod pool;
+mod proxy;
+mod querylog;
+mod ratelimit;
+mod rule;
 mod setting;
+mod socks5;
+mod upstream;
 
 static VERSION: &str = "v2.0.0";
 
@@ -70,6 +82,15 @@ fn serve(matches: &clap::ArgMatches) {
         return;
     }
 
+    // fake ips handed out by dns and relayed by the gateways
+    let pool = match pool::IpPool::load(&setting) {
+        Ok(v) => Arc::new(Mutex::new(v)),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     let cpu = num_cpus::get();
     debug!("num_cpus: {}", cpu);
 
@@ -98,9 +119,10 @@ fn serve(matches: &clap::ArgMatches) {
     let dns_runtime = rt.clone();
 
     bootstrap.block_on(async move {
-        let gateway = gateway::serve(setting.clone());
-        let dns = dns::serve(setting.clone(), dns_runtime);
-        let result = tokio::try_join!(gateway, dns);
+        let gateway = gateway::serve(setting.clone(), pool.clone());
+        let dns = dns::serve(setting.clone(), dns_runtime, pool);
+        let metrics = metrics::serve(setting.clone());
+        let result = tokio::try_join!(gateway, dns, metrics);
         if let Err(e) = result {
             println!("{}", e);
         }
@@ -0,0 +1,292 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::setting::Setting;
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Counter {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Gauge {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Gauge {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set(&self, value: u64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// A gauge per set of labels, written as `key="value",...`.
+pub struct GaugeVec {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl GaugeVec {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        GaugeVec {
+            name,
+            help,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set(&self, labels: &[(&str, &str)], value: u64) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect();
+        let mut values = self.values.lock().unwrap();
+        values.insert(labels.join(","), value);
+    }
+}
+
+pub static DNS_UPSTREAM_ERRORS: Counter = Counter::new(
+    "kungfu_dns_upstream_errors_total",
+    "Upstream lookups failed or timed out.",
+);
+pub static DNS_FALLBACK: Counter = Counter::new(
+    "kungfu_dns_fallback_total",
+    "Lookups retried against dns_fallback.",
+);
+pub static DNS_FALLBACK_ERRORS: Counter = Counter::new(
+    "kungfu_dns_fallback_errors_total",
+    "Fallback lookups failed or timed out, answered with SERVFAIL.",
+);
+
+pub static DNS_REJECTED: Counter = Counter::new(
+    "kungfu_dns_rejected_total",
+    "Queries answered by a reject rule.",
+);
+
+pub static DNS_CACHE_HITS: Counter = Counter::new(
+    "kungfu_dns_cache_hits_total",
+    "Lookups answered from the cache.",
+);
+pub static DNS_CACHE_MISSES: Counter = Counter::new(
+    "kungfu_dns_cache_misses_total",
+    "Lookups not in the cache, sent to the upstream.",
+);
+pub static DNS_CACHE_STALE: Counter = Counter::new(
+    "kungfu_dns_cache_stale_total",
+    "Failed lookups answered with an expired cache entry.",
+);
+pub static DNS_CACHE_PREFETCH: Counter = Counter::new(
+    "kungfu_dns_cache_prefetch_total",
+    "Cache entries refreshed before expiry.",
+);
+
+pub static DNS_DENIED: Counter = Counter::new(
+    "kungfu_dns_denied_total",
+    "Queries refused, from clients outside of dns_allow.",
+);
+pub static DNS_RATE_LIMITED: Counter = Counter::new(
+    "kungfu_dns_rate_limited_total",
+    "Queries dropped by the per client rate limit.",
+);
+pub static DNS_ANY_REFUSED: Counter =
+    Counter::new("kungfu_dns_any_refused_total", "ANY queries refused.");
+
+pub static TCP_CONNECTIONS: Counter = Counter::new(
+    "kungfu_tcp_connections_total",
+    "Tcp connections from the tun relayed to a proxy.",
+);
+pub static TCP_PROXY_ERRORS: Counter = Counter::new(
+    "kungfu_tcp_proxy_errors_total",
+    "Tcp connections failed to connect through the proxy.",
+);
+
+pub static UDP_FLOWS: Counter = Counter::new(
+    "kungfu_udp_flows_total",
+    "Udp flows from the tun relayed to a proxy.",
+);
+pub static UDP_PROXY_ERRORS: Counter = Counter::new(
+    "kungfu_udp_proxy_errors_total",
+    "Udp flows failed to associate with the proxy.",
+);
+
+pub static NAT_EVICTED: Counter = Counter::new(
+    "kungfu_nat_evicted_total",
+    "Nat sessions dropped for a new one while the table is full.",
+);
+
+pub static PROXY_CHECK_FAILURES: Counter = Counter::new(
+    "kungfu_proxy_check_failures_total",
+    "Health checks of proxy endpoints failed or timed out.",
+);
+
+pub static NAT_TCP_SESSIONS: Gauge =
+    Gauge::new("kungfu_nat_tcp_sessions", "Tcp sessions in the nat table.");
+pub static NAT_UDP_SESSIONS: Gauge =
+    Gauge::new("kungfu_nat_udp_sessions", "Udp sessions in the nat table.");
+
+static COUNTERS: &[&Counter] = &[
+    &DNS_UPSTREAM_ERRORS,
+    &DNS_FALLBACK,
+    &DNS_FALLBACK_ERRORS,
+    &DNS_REJECTED,
+    &DNS_CACHE_HITS,
+    &DNS_CACHE_MISSES,
+    &DNS_CACHE_STALE,
+    &DNS_CACHE_PREFETCH,
+    &DNS_DENIED,
+    &DNS_RATE_LIMITED,
+    &DNS_ANY_REFUSED,
+    &TCP_CONNECTIONS,
+    &TCP_PROXY_ERRORS,
+    &UDP_FLOWS,
+    &UDP_PROXY_ERRORS,
+    &NAT_EVICTED,
+    &PROXY_CHECK_FAILURES,
+];
+
+pub static PROXY_UP: GaugeVec = GaugeVec::new(
+    "kungfu_proxy_up",
+    "Whether the proxy endpoint passes its health checks.",
+);
+
+static GAUGES: &[&Gauge] = &[&NAT_TCP_SESSIONS, &NAT_UDP_SESSIONS];
+
+static GAUGE_VECS: &[&GaugeVec] = &[&PROXY_UP];
+
+/// Renders all metrics in prometheus text format.
+pub fn render() -> String {
+    let mut buf = String::new();
+    for counter in COUNTERS {
+        let _ = writeln!(buf, "# HELP {} {}", counter.name, counter.help);
+        let _ = writeln!(buf, "# TYPE {} counter", counter.name);
+        let _ = writeln!(buf, "{} {}", counter.name, counter.get());
+    }
+    for gauge in GAUGES {
+        let _ = writeln!(buf, "# HELP {} {}", gauge.name, gauge.help);
+        let _ = writeln!(buf, "# TYPE {} gauge", gauge.name);
+        let _ = writeln!(buf, "{} {}", gauge.name, gauge.get());
+    }
+    for gauge in GAUGE_VECS {
+        let _ = writeln!(buf, "# HELP {} {}", gauge.name, gauge.help);
+        let _ = writeln!(buf, "# TYPE {} gauge", gauge.name);
+        for (labels, value) in gauge.values.lock().unwrap().iter() {
+            let _ = writeln!(buf, "{}{{{}}} {}", gauge.name, labels, value);
+        }
+    }
+    buf
+}
+
+/// Serves the metrics over http, not at all if `metrics` is empty. Dns and
+/// the gateways keep running without it, a failed listen is only logged.
+pub async fn serve(setting: Arc<Setting>) -> Result<(), String> {
+    if setting.metrics.is_empty() {
+        return Ok(());
+    }
+    let mut listener = match TcpListener::bind(&setting.metrics).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("listen metrics {}, err: {:?}", setting.metrics, e);
+            return Ok(());
+        }
+    };
+    debug!("metrics server start");
+
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("accept metrics connection error: {}", e);
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            // any request gets the metrics
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let body = render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    #[test]
+    fn test_listen_error() {
+        let used = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let yaml = format!(
+            "metrics: \"{}\"\ndns_fallback: [127.0.0.1]\nproxy: []\nrules: []\n",
+            used.local_addr().unwrap()
+        );
+        let setting = Setting::from_yaml(&yaml).unwrap();
+        // returns instead of taking the rest down
+        let mut rt = Runtime::new().unwrap();
+        assert!(rt.block_on(serve(setting)).is_ok());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let setting =
+            Setting::from_yaml("dns_fallback: [127.0.0.1]\nproxy: []\nrules: []\n").unwrap();
+        assert_eq!(setting.metrics, "");
+        let mut rt = Runtime::new().unwrap();
+        assert!(rt.block_on(serve(setting)).is_ok());
+
+        let yaml = "metrics: localhost\ndns_fallback: [127.0.0.1]\nproxy: []\nrules: []\n";
+        let err = Setting::from_yaml(yaml).unwrap_err();
+        assert!(err.to_string().contains("invalid metrics"), "{}", err);
+    }
+}
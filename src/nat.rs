@@ -1 +1,327 @@
-struct Nat {}
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use crate::metrics;
+
+/// Connections redirected from the tun devices to the local relays.
+///
+/// A connection from `src` to `dst` is rewritten to come from `dst.ip` at
+/// an allocated port, so the relay sees the original destination address
+/// as peer and finds the rest of the connection by the port. Replies to
+/// that port are rewritten back.
+///
+/// Sessions expire once idle for the timeout of their protocol and state.
+/// When the table is full the least recently used session is dropped.
+pub struct Nat {
+    sessions: HashMap<(Protocol, u16), Session>,
+    ports: HashMap<Key, u16>,
+    lru: BTreeMap<u64, (Protocol, u16)>,
+    cursors: HashMap<Protocol, u16>,
+    tick: u64,
+    capacity: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Established,
+    /// A FIN or RST was seen, the connection is going away.
+    Closing,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Session {
+    pub protocol: Protocol,
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub state: State,
+    last: Instant,
+    tick: u64,
+}
+
+/// Sessions in the table by protocol.
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub tcp: usize,
+    pub udp: usize,
+}
+
+// protocol, source and destination
+type Key = (Protocol, SocketAddrV4, SocketAddrV4);
+
+// allocated ports, clear of the well known ones
+const PORT_START: u16 = 10000;
+const PORT_END: u16 = 65535;
+
+const TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+const TCP_CLOSING_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+pub const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl Nat {
+    /// A table of up to `capacity` sessions, at most the size of the port
+    /// range.
+    pub fn new(capacity: usize) -> Self {
+        let range = (PORT_END - PORT_START) as usize + 1;
+        Nat {
+            sessions: HashMap::new(),
+            ports: HashMap::new(),
+            lru: BTreeMap::new(),
+            cursors: HashMap::new(),
+            tick: 0,
+            capacity: capacity.clamp(1, range),
+        }
+    }
+
+    /// Returns the port of the connection from `src` to `dst`, allocating
+    /// one if needed. Ports are handed out in turn, skipping the ones in
+    /// use.
+    pub fn allocate(
+        &mut self,
+        protocol: Protocol,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        now: Instant,
+    ) -> u16 {
+        if let Some(&port) = self.ports.get(&(protocol, src, dst)) {
+            if self.get(protocol, port, now).is_some() {
+                return port;
+            }
+        }
+
+        if self.sessions.len() >= self.capacity {
+            self.expire(now);
+        }
+        while self.sessions.len() >= self.capacity {
+            let tick = *self.lru.keys().next().expect("nat lru is empty");
+            let (protocol, port) = self.lru.remove(&tick).unwrap();
+            if let Some(old) = self.sessions.remove(&(protocol, port)) {
+                debug!("evict nat session {} -> {}", old.src, old.dst);
+                self.ports.remove(&(protocol, old.src, old.dst));
+                metrics::NAT_EVICTED.inc();
+            }
+        }
+
+        let port = self.next_port(protocol);
+        let tick = self.next_tick();
+        self.lru.insert(tick, (protocol, port));
+        self.ports.insert((protocol, src, dst), port);
+        self.sessions.insert(
+            (protocol, port),
+            Session {
+                protocol,
+                src,
+                dst,
+                state: State::Established,
+                last: now,
+                tick,
+            },
+        );
+        port
+    }
+
+    /// Returns the live session at `port`, refreshing it.
+    pub fn get(&mut self, protocol: Protocol, port: u16, now: Instant) -> Option<Session> {
+        let session = self.sessions.get(&(protocol, port))?;
+        if session.is_expired(now) {
+            self.remove(protocol, port);
+            return None;
+        }
+
+        let tick = self.next_tick();
+        let session = self.sessions.get_mut(&(protocol, port))?;
+        self.lru.remove(&session.tick);
+        self.lru.insert(tick, (protocol, port));
+        session.tick = tick;
+        session.last = now;
+        Some(*session)
+    }
+
+    /// Marks the session at `port` as closing, it expires sooner.
+    pub fn close(&mut self, protocol: Protocol, port: u16) {
+        if let Some(session) = self.sessions.get_mut(&(protocol, port)) {
+            session.state = State::Closing;
+        }
+    }
+
+    /// Marks the session at `port` as established again, a new connection
+    /// reuses its addresses.
+    pub fn open(&mut self, protocol: Protocol, port: u16) {
+        if let Some(session) = self.sessions.get_mut(&(protocol, port)) {
+            session.state = State::Established;
+        }
+    }
+
+    /// Drops the sessions idle for longer than their timeout.
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<(Protocol, u16)> = self
+            .sessions
+            .iter()
+            .filter(|(_, v)| v.is_expired(now))
+            .map(|(k, _)| *k)
+            .collect();
+        for (protocol, port) in expired {
+            self.remove(protocol, port);
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        for session in self.sessions.values() {
+            match session.protocol {
+                Protocol::Tcp => stats.tcp += 1,
+                Protocol::Udp => stats.udp += 1,
+            }
+        }
+        stats
+    }
+
+    fn remove(&mut self, protocol: Protocol, port: u16) {
+        if let Some(session) = self.sessions.remove(&(protocol, port)) {
+            self.lru.remove(&session.tick);
+            self.ports.remove(&(protocol, session.src, session.dst));
+        }
+    }
+
+    // there is a free port, the capacity is within the range
+    fn next_port(&mut self, protocol: Protocol) -> u16 {
+        let cursor = self.cursors.entry(protocol).or_insert(PORT_END);
+        loop {
+            *cursor = match *cursor {
+                PORT_END => PORT_START,
+                v => v + 1,
+            };
+            if !self.sessions.contains_key(&(protocol, *cursor)) {
+                return *cursor;
+            }
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl Session {
+    fn timeout(&self) -> Duration {
+        match (self.protocol, self.state) {
+            (Protocol::Tcp, State::Established) => TCP_ESTABLISHED_TIMEOUT,
+            (Protocol::Tcp, State::Closing) => TCP_CLOSING_TIMEOUT,
+            (Protocol::Udp, _) => UDP_TIMEOUT,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.last) >= self.timeout()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddrV4 {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allocate() {
+        let mut nat = Nat::new(1024);
+        let now = Instant::now();
+        let src = addr("10.85.0.1:50000");
+        let dst = addr("10.85.0.2:443");
+        let port = nat.allocate(Protocol::Tcp, src, dst, now);
+        assert_eq!(port, PORT_START);
+        assert_eq!(nat.allocate(Protocol::Tcp, src, dst, now), port);
+        let session = nat.get(Protocol::Tcp, port, now).unwrap();
+        assert_eq!((session.src, session.dst), (src, dst));
+        assert_eq!(session.state, State::Established);
+
+        // the port spaces of the protocols are apart
+        assert_eq!(nat.allocate(Protocol::Udp, src, dst, now), PORT_START);
+        assert!(nat.get(Protocol::Udp, PORT_START + 1, now).is_none());
+
+        let other = nat.allocate(Protocol::Tcp, addr("10.85.0.1:50001"), dst, now);
+        assert_eq!(other, PORT_START + 1);
+        assert_eq!(nat.stats(), Stats { tcp: 2, udp: 1 });
+
+        // wraps around, skipping the ports in use
+        nat.cursors.insert(Protocol::Tcp, PORT_END - 1);
+        let a = nat.allocate(Protocol::Tcp, addr("10.85.0.1:1"), dst, now);
+        assert_eq!(a, PORT_END);
+        let b = nat.allocate(Protocol::Tcp, addr("10.85.0.1:2"), dst, now);
+        assert_eq!(b, PORT_START + 2);
+        assert_eq!(nat.get(Protocol::Tcp, port, now).unwrap().src, src);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut nat = Nat::new(1024);
+        let now = Instant::now();
+        let dst = addr("10.85.0.2:443");
+        let tcp = nat.allocate(Protocol::Tcp, addr("10.85.0.1:1"), dst, now);
+        let closing = nat.allocate(Protocol::Tcp, addr("10.85.0.1:2"), dst, now);
+        let udp = nat.allocate(Protocol::Udp, addr("10.85.0.1:3"), dst, now);
+        nat.close(Protocol::Tcp, closing);
+
+        let later = now + UDP_TIMEOUT;
+        assert!(nat.get(Protocol::Udp, udp, later).is_none());
+        assert!(nat.get(Protocol::Tcp, closing, later).is_some());
+        nat.expire(later + TCP_CLOSING_TIMEOUT);
+        assert_eq!(nat.stats(), Stats { tcp: 1, udp: 0 });
+
+        // reopened by a new connection
+        let reused = nat.allocate(Protocol::Tcp, addr("10.85.0.1:4"), dst, now);
+        nat.close(Protocol::Tcp, reused);
+        nat.open(Protocol::Tcp, reused);
+        let later = now + TCP_CLOSING_TIMEOUT;
+        assert_eq!(
+            nat.get(Protocol::Tcp, reused, later).unwrap().state,
+            State::Established
+        );
+        nat.close(Protocol::Tcp, reused);
+        nat.expire(later + TCP_CLOSING_TIMEOUT);
+        assert_eq!(nat.stats(), Stats { tcp: 1, udp: 0 });
+
+        // refreshed by use
+        let later = now + TCP_ESTABLISHED_TIMEOUT - Duration::from_secs(1);
+        assert!(nat.get(Protocol::Tcp, tcp, later).is_some());
+        nat.expire(now + TCP_ESTABLISHED_TIMEOUT);
+        assert_eq!(nat.stats().tcp, 1);
+
+        // an expired session gets a new port
+        let later = later + TCP_ESTABLISHED_TIMEOUT;
+        let port = nat.allocate(Protocol::Tcp, addr("10.85.0.1:1"), dst, later);
+        assert_ne!(port, tcp);
+        assert_eq!(nat.sessions.len(), 1);
+        assert_eq!(nat.ports.len(), 1);
+        assert_eq!(nat.lru.len(), 1);
+    }
+
+    #[test]
+    fn test_evict() {
+        let mut nat = Nat::new(2);
+        let now = Instant::now();
+        let dst = addr("10.85.0.2:443");
+        let a = nat.allocate(Protocol::Tcp, addr("10.85.0.1:1"), dst, now);
+        let b = nat.allocate(Protocol::Tcp, addr("10.85.0.1:2"), dst, now);
+        nat.get(Protocol::Tcp, a, now);
+
+        let evicted = metrics::NAT_EVICTED.get();
+        let c = nat.allocate(Protocol::Udp, addr("10.85.0.1:3"), dst, now);
+        assert!(nat.get(Protocol::Tcp, a, now).is_some());
+        assert!(nat.get(Protocol::Tcp, b, now).is_none());
+        assert!(nat.get(Protocol::Udp, c, now).is_some());
+        assert!(metrics::NAT_EVICTED.get() > evicted);
+        assert_eq!(nat.stats(), Stats { tcp: 1, udp: 1 });
+        assert_eq!(nat.lru.len(), 2);
+    }
+}
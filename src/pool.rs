@@ -0,0 +1,551 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, Write},
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Sender},
+    thread::{self, JoinHandle},
+};
+
+use ipnet::Ipv4Net;
+
+use crate::setting::Setting;
+
+/// Fake ip allocator backed by the `network` setting.
+///
+/// Every network is a segment, a domain is hashed to one of them so the
+/// same domain always lands in the same segment. Inside a segment addresses
+/// are handed out in order, once exhausted the least recently used lease is
+/// recycled.
+pub struct IpPool {
+    segments: Vec<Segment>,
+    hosts: HashMap<String, Ipv4Addr>,
+    leases: HashMap<Ipv4Addr, Lease>,
+    tick: u64,
+    store: Option<Store>,
+}
+
+/// Persisted leases, a snapshot file plus an append log of the changes
+/// since, one `ip domain target` line each. The log is folded into the
+/// snapshot at startup and whenever it outgrows the leases.
+///
+/// The files are written by a thread of their own, the pool is locked for
+/// every query and relayed packet.
+struct Store {
+    sender: Option<Sender<Change>>,
+    writer: Option<JoinHandle<()>>,
+    lines: usize,
+}
+
+enum Change {
+    /// A line to append to the log.
+    Append(String),
+    /// A snapshot replacing the file, emptying the log.
+    Snapshot(String),
+}
+
+// log lines allowed beyond the lease count before compaction
+const STORE_SLACK: usize = 1024;
+
+struct Segment {
+    net: Ipv4Net,
+    gateway: Ipv4Addr,
+    cursor: u32,
+    lru: BTreeMap<u64, Ipv4Addr>,
+}
+
+pub struct Lease {
+    pub domain: String,
+    pub target: String,
+    segment: usize,
+    tick: u64,
+}
+
+impl IpPool {
+    pub fn new(networks: &[String]) -> Result<Self, String> {
+        let mut segments = vec![];
+        for network in networks {
+            let net: Ipv4Net = network
+                .parse()
+                .map_err(|e| format!("invalid network: {}, err: {:?}", network, e))?;
+            let segment = Segment::new(net);
+            if segment.capacity() == 0 {
+                return Err(format!("network too small: {}", network));
+            }
+            segments.push(segment);
+        }
+
+        if segments.is_empty() {
+            return Err("network is empty".to_string());
+        }
+
+        Ok(IpPool {
+            segments,
+            hosts: HashMap::new(),
+            leases: HashMap::new(),
+            tick: 0,
+            store: None,
+        })
+    }
+
+    /// Like `new`, restoring the leases persisted at `path` and persisting
+    /// the following ones, so clients keep their cached fake ips across
+    /// restarts.
+    pub fn open(networks: &[String], path: &str) -> Result<Self, String> {
+        let mut pool = Self::new(networks)?;
+        let err = |e: io::Error| format!("open network file: {}, err: {}", path, e);
+        let path = PathBuf::from(path);
+
+        let log_path = suffixed(&path, "log");
+        for file in [&path, &log_path].iter() {
+            let file = match File::open(file) {
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(err(e)),
+            };
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(err)?;
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                match fields[..] {
+                    [ip, domain, target] => match ip.parse() {
+                        Ok(ip) => pool.restore(ip, domain, target),
+                        Err(_) => warn!("skip network file line: {}", line),
+                    },
+                    // a torn write of the last line
+                    _ => warn!("skip network file line: {}", line),
+                }
+            }
+        }
+        debug!("restored {} fake ips", pool.leases.len());
+
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .map_err(err)?;
+        write_snapshot(&path, &mut log, &pool.snapshot()).map_err(err)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("network-file".to_string())
+            .spawn(move || {
+                for change in receiver {
+                    let result = match change {
+                        Change::Append(line) => log.write_all(line.as_bytes()),
+                        Change::Snapshot(buf) => write_snapshot(&path, &mut log, &buf),
+                    };
+                    if let Err(e) = result {
+                        warn!("write network file, err: {}", e);
+                    }
+                }
+            })
+            .map_err(err)?;
+        pool.store = Some(Store {
+            sender: Some(sender),
+            writer: Some(writer),
+            lines: 0,
+        });
+        Ok(pool)
+    }
+
+    /// The pool of the `network` setting, persisted to `network_file` if
+    /// set.
+    pub fn load(setting: &Setting) -> Result<Self, String> {
+        if setting.network_file.is_empty() {
+            Self::new(&setting.network)
+        } else {
+            Self::open(&setting.network, &setting.network_file)
+        }
+    }
+
+    /// Returns the fake ip of `domain`, allocating one if needed. The lease
+    /// is bound to `target`, the proxy the gateway relays its traffic to.
+    pub fn allocate(&mut self, domain: &str, target: &str) -> Ipv4Addr {
+        let domain = normalize(domain);
+        let tick = self.next_tick();
+
+        if let Some(&ip) = self.hosts.get(&domain) {
+            self.touch(ip, tick);
+            if let Some(lease) = self.leases.get_mut(&ip) {
+                if lease.target != target {
+                    lease.target = target.to_string();
+                    self.persist(ip, &domain, target);
+                }
+            }
+            return ip;
+        }
+
+        let index = self.segment_of(&domain);
+        let leases = &self.leases;
+        let segment = &mut self.segments[index];
+        let ip = match segment.next_free(|ip| leases.contains_key(ip)) {
+            Some(ip) => ip,
+            None => {
+                let ip = segment.evict();
+                if let Some(lease) = self.leases.remove(&ip) {
+                    debug!("recycle fake ip {} from {}", ip, lease.domain);
+                    self.hosts.remove(&lease.domain);
+                }
+                ip
+            }
+        };
+
+        segment.lru.insert(tick, ip);
+        self.persist(ip, &domain, target);
+        self.hosts.insert(domain.clone(), ip);
+        self.leases.insert(
+            ip,
+            Lease {
+                domain,
+                target: target.to_string(),
+                segment: index,
+                tick,
+            },
+        );
+        ip
+    }
+
+    /// Returns the fake ip of `domain` if it has one.
+    pub fn get(&self, domain: &str) -> Option<Ipv4Addr> {
+        self.hosts.get(&normalize(domain)).copied()
+    }
+
+    /// Returns the lease currently bound to `ip`, refreshing it.
+    pub fn lookup(&mut self, ip: &Ipv4Addr) -> Option<&Lease> {
+        if !self.leases.contains_key(ip) {
+            return None;
+        }
+        let tick = self.next_tick();
+        self.touch(*ip, tick);
+        self.leases.get(ip)
+    }
+
+    /// Whether `ip` belongs to one of the networks.
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        self.segments.iter().any(|v| v.net.contains(ip))
+    }
+
+    /// Binds `ip` to `domain` as read from the store, dropping the previous
+    /// lease of either. Addresses outside of the networks are ignored.
+    fn restore(&mut self, ip: Ipv4Addr, domain: &str, target: &str) {
+        let index = match self.segments.iter().position(|v| v.is_usable(ip)) {
+            Some(v) => v,
+            None => return,
+        };
+        let domain = normalize(domain);
+        if let Some(old) = self.hosts.get(&domain).copied() {
+            self.release(old);
+        }
+        self.release(ip);
+
+        let tick = self.next_tick();
+        self.segments[index].lru.insert(tick, ip);
+        self.hosts.insert(domain.clone(), ip);
+        self.leases.insert(
+            ip,
+            Lease {
+                domain,
+                target: target.to_string(),
+                segment: index,
+                tick,
+            },
+        );
+    }
+
+    fn release(&mut self, ip: Ipv4Addr) {
+        if let Some(lease) = self.leases.remove(&ip) {
+            self.segments[lease.segment].lru.remove(&lease.tick);
+            self.hosts.remove(&lease.domain);
+        }
+    }
+
+    fn persist(&mut self, ip: Ipv4Addr, domain: &str, target: &str) {
+        let leases = self.leases.len();
+        let store = match self.store.as_mut() {
+            Some(v) => v,
+            None => return,
+        };
+        store.send(Change::Append(format!("{} {} {}\n", ip, domain, target)));
+        store.lines += 1;
+
+        if store.lines > leases + STORE_SLACK {
+            let snapshot = self.snapshot();
+            if let Some(store) = self.store.as_mut() {
+                store.send(Change::Snapshot(snapshot));
+                store.lines = 0;
+            }
+        }
+    }
+
+    /// All leases, least recently used first so the order survives a
+    /// restore.
+    fn snapshot(&self) -> String {
+        let mut leases: Vec<(&Ipv4Addr, &Lease)> = self.leases.iter().collect();
+        leases.sort_by_key(|(_, lease)| lease.tick);
+        let mut buf = String::new();
+        for (ip, lease) in leases {
+            buf.push_str(&format!("{} {} {}\n", ip, lease.domain, lease.target));
+        }
+        buf
+    }
+
+    fn touch(&mut self, ip: Ipv4Addr, tick: u64) {
+        if let Some(lease) = self.leases.get_mut(&ip) {
+            let segment = &mut self.segments[lease.segment];
+            segment.lru.remove(&lease.tick);
+            segment.lru.insert(tick, ip);
+            lease.tick = tick;
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn segment_of(&self, domain: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        domain.hash(&mut hasher);
+        (hasher.finish() % self.segments.len() as u64) as usize
+    }
+}
+
+impl Store {
+    fn send(&self, change: Change) {
+        if let Some(sender) = &self.sender {
+            if sender.send(change).is_err() {
+                warn!("network file writer is gone");
+            }
+        }
+    }
+}
+
+// the pending writes are done before the pool goes away
+impl Drop for Store {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Segment {
+    fn new(net: Ipv4Net) -> Self {
+        Segment {
+            net,
+            gateway: net.addr(),
+            cursor: 0,
+            lru: BTreeMap::new(),
+        }
+    }
+
+    /// Usable addresses, excluding network, broadcast and gateway address.
+    fn capacity(&self) -> u32 {
+        let size = self.size();
+        let mut n = size.saturating_sub(2);
+        let offset = self.offset(self.gateway);
+        if offset > 0 && offset < size - 1 {
+            n -= 1;
+        }
+        n
+    }
+
+    fn size(&self) -> u32 {
+        1u32.checked_shl(32 - self.net.prefix_len() as u32)
+            .unwrap_or(u32::MAX)
+    }
+
+    fn offset(&self, ip: Ipv4Addr) -> u32 {
+        u32::from(ip) - u32::from(self.net.network())
+    }
+
+    fn next_free<F: Fn(&Ipv4Addr) -> bool>(&mut self, used: F) -> Option<Ipv4Addr> {
+        let last = self.size().saturating_sub(1);
+        while self.cursor + 1 < last {
+            self.cursor += 1;
+            let ip = Ipv4Addr::from(u32::from(self.net.network()) + self.cursor);
+            // restored leases are spread over the segment
+            if ip != self.gateway && !used(&ip) {
+                return Some(ip);
+            }
+        }
+        None
+    }
+
+    fn is_usable(&self, ip: Ipv4Addr) -> bool {
+        if !self.net.contains(&ip) {
+            return false;
+        }
+        let offset = self.offset(ip);
+        ip != self.gateway && offset > 0 && offset < self.size() - 1
+    }
+
+    fn evict(&mut self) -> Ipv4Addr {
+        let tick = *self.lru.keys().next().expect("segment lru is empty");
+        self.lru.remove(&tick).unwrap()
+    }
+}
+
+/// Replaces the file at `path` with `buf`, then empties the log.
+fn write_snapshot(path: &Path, log: &mut File, buf: &str) -> io::Result<()> {
+    let tmp = suffixed(path, "tmp");
+    fs::write(&tmp, buf)?;
+    fs::rename(&tmp, path)?;
+    log.set_len(0)
+}
+
+/// `path` with `.suffix` appended.
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pool(networks: &[&str]) -> IpPool {
+        let networks: Vec<String> = networks.iter().map(|v| v.to_string()).collect();
+        IpPool::new(&networks).unwrap()
+    }
+
+    #[test]
+    fn test_allocate_stable() {
+        let mut pool = pool(&["10.85.0.1/16", "10.86.0.1/16"]);
+        let a = pool.allocate("www.google.com.", "hk");
+        let b = pool.allocate("mail.google.com", "hk");
+        assert_ne!(a, b);
+        assert_eq!(a, pool.allocate("WWW.Google.com", "jp"));
+        assert!(pool.contains(&a));
+        assert_ne!(a, "10.85.0.1".parse::<Ipv4Addr>().unwrap());
+        let lease = pool.lookup(&a).unwrap();
+        assert_eq!(lease.domain, "www.google.com");
+        assert_eq!(lease.target, "jp");
+        assert!(pool.lookup(&"10.85.255.254".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_skip_gateway() {
+        let mut pool = pool(&["10.85.0.2/30"]);
+        assert_eq!(pool.segments[0].capacity(), 1);
+        assert_eq!(
+            pool.allocate("a.com", "hk"),
+            "10.85.0.1".parse::<Ipv4Addr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_recycle_lru() {
+        let mut pool = pool(&["10.85.0.1/29"]);
+        // 10.85.0.2 ~ 10.85.0.6
+        let ips: Vec<Ipv4Addr> = (0..5)
+            .map(|i| pool.allocate(&format!("{}.com", i), "hk"))
+            .collect();
+        assert!(!ips.contains(&"10.85.0.1".parse().unwrap()));
+
+        // refresh 0.com, 1.com becomes the least recently used
+        pool.lookup(&ips[0]);
+        let ip = pool.allocate("new.com", "hk");
+        assert_eq!(ip, ips[1]);
+        assert_eq!(pool.lookup(&ip).unwrap().domain, "new.com");
+        assert_eq!(pool.allocate("0.com", "hk"), ips[0]);
+        assert_ne!(pool.allocate("1.com", "hk"), ips[1]);
+    }
+
+    #[test]
+    fn test_invalid_network() {
+        assert!(IpPool::new(&["10.85.0.1/32".to_string()]).is_err());
+        assert!(IpPool::new(&["fake".to_string()]).is_err());
+        assert!(IpPool::new(&[]).is_err());
+    }
+
+    #[test]
+    fn test_open() {
+        let dir = std::env::temp_dir().join(format!("kungfu-pool-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("kungfu.pool");
+        let path = path.to_str().unwrap();
+        let networks = vec!["10.85.0.1/29".to_string()];
+
+        let mut pool = IpPool::open(&networks, path).unwrap();
+        let ips: Vec<Ipv4Addr> = (0..5)
+            .map(|i| pool.allocate(&format!("{}.com", i), "hk"))
+            .collect();
+        pool.allocate("3.com", "jp");
+        // recycles 0.com
+        let new = pool.allocate("new.com", "hk");
+        assert_eq!(new, ips[0]);
+        drop(pool);
+
+        let log = fs::read_to_string(format!("{}.log", path)).unwrap();
+        assert_eq!(log.lines().count(), 7);
+
+        let mut pool = IpPool::open(&networks, path).unwrap();
+        assert_eq!(fs::read_to_string(format!("{}.log", path)).unwrap(), "");
+        assert_eq!(fs::read_to_string(path).unwrap().lines().count(), 5);
+        assert_eq!(pool.get("new.com"), Some(new));
+        assert_eq!(pool.get("0.com"), None);
+        assert_eq!(pool.lookup(&ips[3]).unwrap().target, "jp");
+        for (i, ip) in ips.iter().enumerate().skip(1) {
+            assert_eq!(pool.allocate(&format!("{}.com", i), "hk"), *ip);
+        }
+        // least recently used first
+        assert_eq!(pool.allocate("0.com", "hk"), new);
+        drop(pool);
+
+        // a torn line and addresses outside of the networks are skipped
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(b"10.86.0.2 a.com hk\n10.85.0.2 b.com")
+            .unwrap();
+        let networks = vec!["10.85.0.1/29".to_string(), "10.86.0.1/29".to_string()];
+        let mut pool = IpPool::open(&networks, path).unwrap();
+        assert_eq!(pool.leases.len(), 6);
+        assert!(pool.get("b.com").is_none());
+        assert_eq!(pool.get("a.com"), Some("10.86.0.2".parse().unwrap()));
+        // restored addresses are not handed out again
+        for domain in ["c.com", "d.com", "e.com"].iter() {
+            let ip = pool.allocate(domain, "hk");
+            assert_ne!(ip, "10.86.0.2".parse::<Ipv4Addr>().unwrap());
+        }
+        assert_eq!(pool.get("a.com"), Some("10.86.0.2".parse().unwrap()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = std::env::temp_dir().join(format!("kungfu-compact-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("kungfu.pool");
+        let path = path.to_str().unwrap();
+        let networks = vec!["10.85.0.1/29".to_string()];
+
+        let mut pool = IpPool::open(&networks, path).unwrap();
+        let ip = pool.allocate("a.com", "hk");
+        for i in 0..STORE_SLACK + 10 {
+            pool.allocate("a.com", if i % 2 == 0 { "jp" } else { "hk" });
+        }
+        drop(pool);
+
+        // folded into the snapshot by the writer
+        let log = fs::read_to_string(format!("{}.log", path)).unwrap();
+        assert!(log.lines().count() < 10);
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            format!("{} a.com jp\n", ip)
+        );
+        let pool = IpPool::open(&networks, path).unwrap();
+        assert_eq!(pool.get("a.com"), Some(ip));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
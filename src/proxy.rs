@@ -0,0 +1,389 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::future::join_all;
+
+use crate::{
+    metrics,
+    setting::{Endpoint, Proxy, Setting},
+    socks5::{self, Address},
+};
+
+/// Endpoints of the `proxy` groups, one chosen per connection.
+///
+/// Connections are spread over a group by weight, with smooth weighted
+/// round robin. A sticky group hashes the destination instead, weighted
+/// rendezvous hashing keeps most destinations in place as the endpoints
+/// change.
+///
+/// Endpoints are checked by connecting through them to a test target. One
+/// goes down after `fall` failed checks in a row and up again after `rise`
+/// passed ones. The endpoints down are left out of the selection, unless
+/// the whole group is down.
+pub struct Proxies {
+    groups: HashMap<String, Group>,
+    rise: u32,
+    fall: u32,
+}
+
+struct Group {
+    endpoints: Vec<Endpoint>,
+    sticky: bool,
+    state: Mutex<State>,
+}
+
+struct State {
+    // current weights of the round robin
+    current: Vec<i64>,
+    health: Vec<Health>,
+}
+
+#[derive(Clone, Copy)]
+struct Health {
+    up: bool,
+    // checks in a row against `up`
+    streak: u32,
+}
+
+impl Proxies {
+    /// Groups of `proxies`, every endpoint up to begin with.
+    pub fn new(proxies: &[Proxy], rise: u32, fall: u32) -> Self {
+        let groups = proxies
+            .iter()
+            .map(|v| {
+                let len = v.values.len();
+                for endpoint in v.values.iter() {
+                    set_up(&v.name, endpoint, true);
+                }
+                let group = Group {
+                    endpoints: v.values.clone(),
+                    sticky: v.sticky,
+                    state: Mutex::new(State {
+                        current: vec![0; len],
+                        health: vec![
+                            Health {
+                                up: true,
+                                streak: 0
+                            };
+                            len
+                        ],
+                    }),
+                };
+                (v.name.clone(), group)
+            })
+            .collect();
+        Proxies {
+            groups,
+            rise: rise.max(1),
+            fall: fall.max(1),
+        }
+    }
+
+    /// The endpoint of group `name` for a connection to `target`.
+    pub fn select(&self, name: &str, target: &Address) -> Option<Endpoint> {
+        let group = self.groups.get(name)?;
+        let index = if group.sticky {
+            group.hash(target)
+        } else {
+            group.next()
+        }?;
+        Some(group.endpoints[index].clone())
+    }
+
+    /// Checks every endpoint once, connecting to `target` through it.
+    async fn probe(&self, target: &Address, timeout: Duration) {
+        let mut checks = vec![];
+        for (name, group) in self.groups.iter() {
+            for (index, endpoint) in group.endpoints.iter().enumerate() {
+                checks.push(async move {
+                    let result =
+                        tokio::time::timeout(timeout, socks5::connect(endpoint, target)).await;
+                    let ok = match result {
+                        Ok(Ok(_)) => true,
+                        Ok(Err(e)) => {
+                            debug!("check proxy {} {} failed: {}", name, endpoint, e);
+                            false
+                        }
+                        Err(_) => {
+                            debug!("check proxy {} {} timed out", name, endpoint);
+                            false
+                        }
+                    };
+                    if !ok {
+                        metrics::PROXY_CHECK_FAILURES.inc();
+                    }
+                    self.report(name, index, ok);
+                });
+            }
+        }
+        join_all(checks).await;
+    }
+
+    /// Records a check of endpoint `index` of group `name`.
+    fn report(&self, name: &str, index: usize, ok: bool) {
+        let group = match self.groups.get(name) {
+            Some(v) => v,
+            None => return,
+        };
+        let mut state = group.state.lock().unwrap();
+        let health = &mut state.health[index];
+        if health.up == ok {
+            health.streak = 0;
+            return;
+        }
+        health.streak += 1;
+        let threshold = if health.up { self.fall } else { self.rise };
+        if health.streak < threshold {
+            return;
+        }
+        health.up = ok;
+        health.streak = 0;
+
+        let endpoint = &group.endpoints[index];
+        if ok {
+            info!("proxy {} {} is up", name, endpoint);
+        } else {
+            warn!("proxy {} {} is down", name, endpoint);
+        }
+        set_up(name, endpoint, ok);
+    }
+}
+
+/// Checks the proxy endpoints every `proxy_check_interval` seconds, never
+/// if it is 0.
+pub async fn check(proxies: Arc<Proxies>, setting: Arc<Setting>) {
+    if setting.proxy_check_interval == 0 {
+        return;
+    }
+    let target: Address = match setting.proxy_check_target.parse() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("invalid proxy_check_target: {}", e);
+            return;
+        }
+    };
+    let timeout = Duration::from_secs(setting.proxy_check_timeout as u64);
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(setting.proxy_check_interval as u64));
+    loop {
+        interval.tick().await;
+        proxies.probe(&target, timeout).await;
+    }
+}
+
+fn set_up(name: &str, endpoint: &Endpoint, up: bool) {
+    let endpoint = endpoint.to_string();
+    let labels = [("proxy", name), ("endpoint", endpoint.as_str())];
+    metrics::PROXY_UP.set(&labels, up as u64);
+}
+
+impl Group {
+    // the endpoints up, or all of them if none is
+    fn candidates(&self, state: &State) -> Vec<usize> {
+        let up: Vec<usize> = (0..self.endpoints.len())
+            .filter(|&i| state.health[i].up)
+            .collect();
+        if up.is_empty() {
+            (0..self.endpoints.len()).collect()
+        } else {
+            up
+        }
+    }
+
+    fn next(&self) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+        let candidates = self.candidates(&state);
+        let mut total = 0;
+        for &i in candidates.iter() {
+            let weight = self.endpoints[i].weight as i64;
+            state.current[i] += weight;
+            total += weight;
+        }
+        let current = &mut state.current;
+        let index = candidates
+            .into_iter()
+            .max_by_key(|&i| (current[i], -(i as i64)))?;
+        current[index] -= total;
+        Some(index)
+    }
+
+    fn hash(&self, target: &Address) -> Option<usize> {
+        let score = |endpoint: &Endpoint| {
+            let mut hasher = DefaultHasher::new();
+            target.hash(&mut hasher);
+            endpoint.addr().hash(&mut hasher);
+            // uniform in (0, 1)
+            let u = ((hasher.finish() >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            endpoint.weight as f64 / -u.ln()
+        };
+        let state = self.state.lock().unwrap();
+        self.candidates(&state)
+            .into_iter()
+            .map(|i| (i, score(&self.endpoints[i])))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{net::TcpListener, runtime::Runtime};
+
+    use super::*;
+
+    fn proxies(sticky: bool, values: &[&str]) -> Proxies {
+        Proxies::new(
+            &[Proxy {
+                name: "hk".to_string(),
+                values: values.iter().map(|v| v.parse().unwrap()).collect(),
+                sticky,
+            }],
+            2,
+            3,
+        )
+    }
+
+    fn target(i: usize) -> Address {
+        Address::Domain(format!("{}.example.com", i), 443)
+    }
+
+    #[test]
+    fn test_weighted() {
+        let proxies = proxies(
+            false,
+            &[
+                "socks5://127.0.0.1:1082?weight=1",
+                "socks5://127.0.0.1:1083?weight=2",
+            ],
+        );
+        let ports: Vec<u16> = (0..6)
+            .map(|i| proxies.select("hk", &target(i)).unwrap().port)
+            .collect();
+        assert_eq!(ports, vec![1083, 1082, 1083, 1083, 1082, 1083]);
+        assert!(proxies.select("jp", &target(0)).is_none());
+    }
+
+    #[test]
+    fn test_sticky() {
+        let values = [
+            "socks5://127.0.0.1:1082?weight=1",
+            "socks5://127.0.0.1:1083?weight=3",
+        ];
+        let sticky = proxies(true, &values);
+        let mut counts = [0; 2];
+        for i in 0..1000 {
+            let endpoint = sticky.select("hk", &target(i)).unwrap();
+            assert_eq!(sticky.select("hk", &target(i)).unwrap(), endpoint);
+            counts[(endpoint.port - 1082) as usize] += 1;
+        }
+        // about 1:3
+        assert!(counts[0] > 150 && counts[0] < 350, "{:?}", counts);
+
+        // destinations stay when an endpoint is added
+        let more = proxies(true, &[values[0], values[1], "socks5://127.0.0.1:1084"]);
+        let moved = (0..1000)
+            .filter(|&i| {
+                let port = more.select("hk", &target(i)).unwrap().port;
+                port != 1084 && port != sticky.select("hk", &target(i)).unwrap().port
+            })
+            .count();
+        assert_eq!(moved, 0);
+    }
+
+    #[test]
+    fn test_health() {
+        let values = ["socks5://127.0.0.1:1082", "socks5://127.0.0.1:1083"];
+        let ports = |group: &Proxies| -> Vec<u16> {
+            (0..4)
+                .map(|i| group.select("hk", &target(i)).unwrap().port)
+                .collect()
+        };
+        let weighted = proxies(false, &values);
+
+        // down after 3 failures in a row
+        weighted.report("hk", 0, false);
+        weighted.report("hk", 0, false);
+        weighted.report("hk", 0, true);
+        weighted.report("hk", 0, false);
+        weighted.report("hk", 0, false);
+        assert_eq!(ports(&weighted), vec![1082, 1083, 1082, 1083]);
+        weighted.report("hk", 0, false);
+        assert_eq!(ports(&weighted), vec![1083; 4]);
+
+        // all down, all used
+        for _ in 0..3 {
+            weighted.report("hk", 1, false);
+        }
+        assert_eq!(ports(&weighted).len(), 4);
+        assert!(ports(&weighted).contains(&1082));
+
+        // up after 2 passes
+        weighted.report("hk", 1, true);
+        weighted.report("hk", 1, true);
+        assert_eq!(ports(&weighted), vec![1083; 4]);
+
+        // sticky groups move off the endpoints down
+        let sticky = proxies(true, &values);
+        for _ in 0..3 {
+            sticky.report("hk", 0, false);
+        }
+        assert!((0..100).all(|i| sticky.select("hk", &target(i)).unwrap().port == 1083));
+    }
+
+    #[test]
+    fn test_probe() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (addr, _targets) = socks5::test::stub_server().await;
+            // a port nobody listens on
+            let dead = TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let proxies = Proxies::new(
+                &[Proxy {
+                    name: "probe".to_string(),
+                    values: vec![socks5::test::endpoint(addr), socks5::test::endpoint(dead)],
+                    sticky: false,
+                }],
+                1,
+                2,
+            );
+            let target = "www.gstatic.com:80".parse().unwrap();
+            let failures = metrics::PROXY_CHECK_FAILURES.get();
+            proxies.probe(&target, Duration::from_secs(5)).await;
+            assert!(metrics::PROXY_CHECK_FAILURES.get() > failures);
+            let up = format!(
+                "kungfu_proxy_up{{proxy=\"probe\",endpoint=\"socks5://{}\"}} 1",
+                dead
+            );
+            assert!(metrics::render().contains(&up));
+
+            proxies.probe(&target, Duration::from_secs(5)).await;
+            for _ in 0..4 {
+                let endpoint = proxies.select("probe", &target).unwrap();
+                assert_eq!(endpoint.port, addr.port());
+            }
+            let down = format!(
+                "kungfu_proxy_up{{proxy=\"probe\",endpoint=\"socks5://{}\"}} 0",
+                dead
+            );
+            assert!(metrics::render().contains(&down));
+
+            // refused by the proxy
+            let refused = "127.0.0.1:1".parse().unwrap();
+            proxies.probe(&refused, Duration::from_secs(5)).await;
+            proxies.probe(&refused, Duration::from_secs(5)).await;
+            let up = format!(
+                "kungfu_proxy_up{{proxy=\"probe\",endpoint=\"socks5://{}\"}} 0",
+                addr
+            );
+            assert!(metrics::render().contains(&up));
+        });
+    }
+}
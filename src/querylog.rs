@@ -0,0 +1,109 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    sync::Mutex,
+};
+
+use chrono::Local;
+
+/// Query log, one json object per line appended to a file, telling how
+/// each query was answered.
+pub struct QueryLog {
+    file: Mutex<File>,
+}
+
+#[derive(Debug, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct Entry {
+    pub time: String,
+    pub client: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub query_type: String,
+    /// Type of the rule deciding the answer, or `hosts` and `pool` for
+    /// hosts entries and fake ips leased before.
+    pub rule: Option<String>,
+    /// Index of the rule in `rules`.
+    pub rule_index: Option<usize>,
+    pub target: Option<String>,
+    pub answers: Vec<IpAddr>,
+    /// Upstream host of the answer, `cache` or `stale` when cached.
+    pub upstream: Option<String>,
+    pub latency_ms: f64,
+    pub rcode: String,
+}
+
+impl QueryLog {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("open dns query log: {}, err: {}", path, e))?;
+        Ok(QueryLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(&self, mut entry: Entry) {
+        entry.time = Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string();
+        let mut line = match serde_json::to_string(&entry) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("encode dns query log, err: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("write dns query log, err: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let path = env::temp_dir().join(format!("kungfu-query-{}.log", process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+
+        let log = QueryLog::open(&path).unwrap();
+        log.write(Entry {
+            client: "127.0.0.1".to_string(),
+            name: "www.google.com.".to_string(),
+            query_type: "A".to_string(),
+            rule: Some("domain".to_string()),
+            rule_index: Some(2),
+            target: Some("v2ray_hk".to_string()),
+            answers: vec!["10.85.0.2".parse().unwrap()],
+            latency_ms: 0.25,
+            rcode: "NoError".to_string(),
+            ..Entry::default()
+        });
+        log.write(Entry {
+            name: "www.qq.com.".to_string(),
+            ..Entry::default()
+        });
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#""type":"A""#));
+        assert!(lines[0].contains(r#""answers":["10.85.0.2"]"#));
+        let entry: Entry = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(entry.rule_index, Some(2));
+        assert_eq!(entry.target.as_deref(), Some("v2ray_hk"));
+        assert!(!entry.time.is_empty());
+        let entry: Entry = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(entry.upstream, None);
+
+        assert!(QueryLog::open("/nonexistent/query.log").is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    time::Instant,
+};
+
+/// Token bucket per client, `rate` queries a second with bursts up to
+/// `burst`. Ipv6 clients share the bucket of their /64.
+///
+/// At most `capacity` clients are tracked, the least recently seen one is
+/// dropped for a new one. A dropped client starts over with a full bucket.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<IpAddr, Bucket>,
+    lru: BTreeMap<u64, IpAddr>,
+    tick: u64,
+    capacity: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    tick: u64,
+}
+
+// clients tracked before the least recently seen are dropped
+const MAX_CLIENTS: usize = 65536;
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            buckets: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            capacity: MAX_CLIENTS,
+        }
+    }
+
+    /// Takes a token of `client`, false if there is none left.
+    pub fn allow(&mut self, client: IpAddr, now: Instant) -> bool {
+        let client = key(client);
+        if !self.buckets.contains_key(&client) && self.buckets.len() >= self.capacity {
+            self.evict();
+        }
+
+        self.tick += 1;
+        let (rate, burst, tick) = (self.rate, self.burst, self.tick);
+        let bucket = self.buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            tick,
+        });
+        self.lru.remove(&bucket.tick);
+        self.lru.insert(tick, client);
+        bucket.tick = tick;
+
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn evict(&mut self) {
+        let tick = match self.lru.keys().next() {
+            Some(v) => *v,
+            None => return,
+        };
+        if let Some(client) = self.lru.remove(&tick) {
+            self.buckets.remove(&client);
+        }
+    }
+}
+
+fn key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            octets[8..].iter_mut().for_each(|v| *v = 0);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_allow() {
+        let mut limiter = RateLimiter::new(10, 5);
+        let now = Instant::now();
+        let a: IpAddr = "198.51.100.1".parse().unwrap();
+        let b: IpAddr = "198.51.100.2".parse().unwrap();
+
+        for _ in 0..5 {
+            assert!(limiter.allow(a, now));
+        }
+        assert!(!limiter.allow(a, now));
+        assert!(limiter.allow(b, now));
+
+        // 10 a second
+        let later = now + Duration::from_millis(100);
+        assert!(limiter.allow(a, later));
+        assert!(!limiter.allow(a, later));
+        let later = now + Duration::from_secs(10);
+        for _ in 0..5 {
+            assert!(limiter.allow(a, later));
+        }
+        assert!(!limiter.allow(a, later));
+
+        // a /64 shares a bucket
+        let mut limiter = RateLimiter::new(1, 1);
+        assert!(limiter.allow("2001:db8::1".parse().unwrap(), now));
+        assert!(!limiter.allow("2001:db8::ffff:2".parse().unwrap(), now));
+        assert!(limiter.allow("2001:db8:0:1::1".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn test_evict() {
+        let mut limiter = RateLimiter::new(1, 1);
+        limiter.capacity = 2;
+        let now = Instant::now();
+        let a: IpAddr = "198.51.100.1".parse().unwrap();
+        let b: IpAddr = "198.51.100.2".parse().unwrap();
+        assert!(limiter.allow(a, now));
+        assert!(limiter.allow(b, now));
+        assert!(!limiter.allow(a, now));
+
+        // b is the least recently seen
+        for i in 3..100 {
+            limiter.allow(format!("198.51.100.{}", i).parse().unwrap(), now);
+            assert!(!limiter.allow(a, now));
+        }
+        assert_eq!(limiter.buckets.len(), 2);
+        assert_eq!(limiter.lru.len(), 2);
+        assert!(limiter.allow(b, now));
+    }
+}
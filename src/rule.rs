@@ -0,0 +1,336 @@
+use std::{collections::HashMap, net::Ipv4Addr};
+
+use ipnet::Ipv4Net;
+
+use crate::setting::{Rule, RuleType};
+
+/// Compiled `domain` rules.
+///
+/// Supported patterns:
+/// - `www.google.com`, exact name
+/// - `*.google.com`, any subdomain of `google.com`
+/// - `.google.com`, `google.com` itself and any subdomain
+/// - `*google*`, `www.google.?o`, full glob, `*` may cross labels
+///
+/// Patterns are numbered in config order, the first one matching a name
+/// wins regardless of its kind.
+pub struct DomainMatcher {
+    root: Node,
+    globs: Vec<(usize, Vec<u8>)>,
+    // pattern index -> rule index
+    rules: Vec<usize>,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    exact: Option<usize>,
+    wildcard: Option<usize>,
+    suffix: Option<usize>,
+}
+
+enum Pattern {
+    Exact,
+    Wildcard,
+    Suffix,
+    Glob,
+}
+
+impl DomainMatcher {
+    pub fn new(rules: &[Rule]) -> Result<Self, String> {
+        let mut matcher = DomainMatcher {
+            root: Node::default(),
+            globs: vec![],
+            rules: vec![],
+        };
+
+        for (i, rule) in rules.iter().enumerate() {
+            if rule.rule_type != RuleType::Domain {
+                continue;
+            }
+            for value in &rule.values {
+                matcher
+                    .insert(value, i)
+                    .map_err(|e| format!("invalid domain rule: {}, err: {}", value, e))?;
+            }
+        }
+
+        Ok(matcher)
+    }
+
+    /// Returns the index of the first rule matching `name`.
+    pub fn find(&self, name: &str) -> Option<usize> {
+        let name = normalize(name);
+        if name.is_empty() {
+            return None;
+        }
+
+        let labels: Vec<&str> = name.rsplit('.').collect();
+        let mut best: Option<usize> = None;
+        let mut node = &self.root;
+        for (depth, label) in labels.iter().enumerate() {
+            node = match node.children.get(*label) {
+                Some(v) => v,
+                None => break,
+            };
+            let last = depth + 1 == labels.len();
+            best = min(best, node.suffix);
+            if last {
+                best = min(best, node.exact);
+            } else {
+                best = min(best, node.wildcard);
+            }
+        }
+
+        for (index, glob) in &self.globs {
+            if best.map_or(false, |v| v < *index) {
+                break;
+            }
+            if glob_match(glob, name.as_bytes()) {
+                best = Some(*index);
+                break;
+            }
+        }
+
+        best.map(|v| self.rules[v])
+    }
+
+    fn insert(&mut self, value: &str, rule: usize) -> Result<(), String> {
+        let value = normalize(value);
+        let (pattern, name) = parse(&value)?;
+
+        let index = self.rules.len();
+        self.rules.push(rule);
+
+        let name = match pattern {
+            Pattern::Glob => {
+                self.globs.push((index, name.as_bytes().to_vec()));
+                return Ok(());
+            }
+            _ => name,
+        };
+
+        let mut node = &mut self.root;
+        for label in name.rsplit('.') {
+            node = node.children.entry(label.to_string()).or_default();
+        }
+        let slot = match pattern {
+            Pattern::Exact => &mut node.exact,
+            Pattern::Wildcard => &mut node.wildcard,
+            _ => &mut node.suffix,
+        };
+        // keep the earlier pattern on duplicates
+        slot.get_or_insert(index);
+        Ok(())
+    }
+}
+
+/// Compiled `dnsCidr` rules, matched against resolved addresses.
+pub struct CidrMatcher {
+    nets: Vec<(Ipv4Net, usize)>,
+}
+
+impl CidrMatcher {
+    pub fn new(rules: &[Rule]) -> Result<Self, String> {
+        let mut nets = vec![];
+        for (i, rule) in rules.iter().enumerate() {
+            if rule.rule_type != RuleType::DnsCidr {
+                continue;
+            }
+            for value in &rule.values {
+                let net: Ipv4Net = value
+                    .parse()
+                    .map_err(|e| format!("invalid dnsCidr rule: {}, err: {:?}", value, e))?;
+                nets.push((net.trunc(), i));
+            }
+        }
+        Ok(CidrMatcher { nets })
+    }
+
+    /// Returns the index of the first rule containing any of `ips`.
+    pub fn find(&self, ips: &[Ipv4Addr]) -> Option<usize> {
+        self.nets
+            .iter()
+            .find(|(net, _)| ips.iter().any(|ip| net.contains(ip)))
+            .map(|(_, i)| *i)
+    }
+}
+
+fn parse(value: &str) -> Result<(Pattern, &str), String> {
+    let (pattern, name) = if let Some(name) = value.strip_prefix("*.") {
+        (Pattern::Wildcard, name)
+    } else if let Some(name) = value.strip_prefix('.') {
+        (Pattern::Suffix, name)
+    } else {
+        (Pattern::Exact, value)
+    };
+
+    if name.is_empty() {
+        return Err("empty pattern".to_string());
+    }
+
+    if name.contains('*') || name.contains('?') {
+        return Ok((Pattern::Glob, value));
+    }
+
+    if name.split('.').any(|v| v.is_empty()) {
+        return Err("empty label".to_string());
+    }
+
+    Ok((pattern, name))
+}
+
+fn min(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_lowercase()
+}
+
+/// Whether `target` blocks the matched names and traffic instead of
+/// relaying it to a proxy.
+pub fn is_reject(target: &str) -> bool {
+    target == "reject" || target == "block"
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((sp, sn)) = star {
+            p = sp + 1;
+            n = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&v| v == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(values: &[&str]) -> Rule {
+        Rule {
+            rule_type: RuleType::Domain,
+            target: "proxy".to_string(),
+            values: values.iter().map(|v| v.to_string()).collect(),
+            upstream: None,
+        }
+    }
+
+    #[test]
+    fn test_patterns() {
+        let rules = vec![
+            rule(&["www.example.com"]),
+            rule(&["*.google.com"]),
+            rule(&[".youtube.com"]),
+            rule(&["*facebook*", "ad?.example.org"]),
+        ];
+        let matcher = DomainMatcher::new(&rules).unwrap();
+
+        assert_eq!(matcher.find("www.example.com."), Some(0));
+        assert_eq!(matcher.find("WWW.Example.COM"), Some(0));
+        assert_eq!(matcher.find("example.com"), None);
+        assert_eq!(matcher.find("a.www.example.com"), None);
+
+        assert_eq!(matcher.find("www.google.com"), Some(1));
+        assert_eq!(matcher.find("a.b.google.com."), Some(1));
+        assert_eq!(matcher.find("google.com"), None);
+
+        assert_eq!(matcher.find("youtube.com"), Some(2));
+        assert_eq!(matcher.find("m.youtube.com"), Some(2));
+        assert_eq!(matcher.find("notyoutube.com"), None);
+
+        assert_eq!(matcher.find("static.facebook.net"), Some(3));
+        assert_eq!(matcher.find("ads.example.org"), Some(3));
+        assert_eq!(matcher.find("adsx.example.org"), None);
+        assert_eq!(matcher.find(""), None);
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let rules = vec![
+            rule(&["*.cdn.example.com"]),
+            Rule {
+                rule_type: RuleType::Route,
+                target: "proxy".to_string(),
+                values: vec!["10.0.0.0/8".to_string()],
+                upstream: None,
+            },
+            rule(&["*example*", ".example.com", "a.cdn.example.com"]),
+        ];
+        let matcher = DomainMatcher::new(&rules).unwrap();
+
+        assert_eq!(matcher.find("a.cdn.example.com"), Some(0));
+        assert_eq!(matcher.find("www.example.com"), Some(2));
+
+        let rules = vec![rule(&["*example*"]), rule(&["www.example.com"])];
+        let matcher = DomainMatcher::new(&rules).unwrap();
+        assert_eq!(matcher.find("www.example.com"), Some(0));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(DomainMatcher::new(&[rule(&["*.a..com"])]).is_err());
+        assert!(DomainMatcher::new(&[rule(&["a..com"])]).is_err());
+        assert!(DomainMatcher::new(&[rule(&[""])]).is_err());
+    }
+
+    #[test]
+    fn test_cidr() {
+        let rules = vec![
+            Rule {
+                rule_type: RuleType::DnsCidr,
+                target: "hk".to_string(),
+                values: vec!["39.156.69.79/32".to_string(), "182.61.200.6/24".to_string()],
+                upstream: None,
+            },
+            rule(&["*.google.com"]),
+            Rule {
+                rule_type: RuleType::DnsCidr,
+                target: "jp".to_string(),
+                values: vec!["182.61.0.0/16".to_string()],
+                upstream: None,
+            },
+        ];
+        let matcher = CidrMatcher::new(&rules).unwrap();
+
+        let ips = |v: &[&str]| -> Vec<Ipv4Addr> { v.iter().map(|v| v.parse().unwrap()).collect() };
+        assert_eq!(matcher.find(&ips(&["39.156.69.79"])), Some(0));
+        assert_eq!(matcher.find(&ips(&["1.1.1.1", "182.61.200.100"])), Some(0));
+        assert_eq!(matcher.find(&ips(&["182.61.100.1"])), Some(2));
+        assert_eq!(matcher.find(&ips(&["39.156.69.80"])), None);
+        assert_eq!(matcher.find(&[]), None);
+
+        assert!(CidrMatcher::new(&[Rule {
+            rule_type: RuleType::DnsCidr,
+            target: "hk".to_string(),
+            values: vec!["39.156.69".to_string()],
+            upstream: None,
+        }])
+        .is_err());
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob_match(b"*", b"a.b"));
+        assert!(glob_match(b"a*c", b"abbc"));
+        assert!(glob_match(b"a?c", b"abc"));
+        assert!(!glob_match(b"a?c", b"ac"));
+        assert!(!glob_match(b"a*d", b"abc"));
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::setting::Endpoint;
+
+const VERSION: u8 = 5;
+const METHOD_NONE: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Target of a request, a domain is resolved by the proxy.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Address {
+    fn encode(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Address::Ip(SocketAddr::V4(v)) => {
+                buf.push(ATYP_IPV4);
+                buf.extend_from_slice(&v.ip().octets());
+                buf.extend_from_slice(&v.port().to_be_bytes());
+            }
+            Address::Ip(SocketAddr::V6(v)) => {
+                buf.push(ATYP_IPV6);
+                buf.extend_from_slice(&v.ip().octets());
+                buf.extend_from_slice(&v.port().to_be_bytes());
+            }
+            Address::Domain(domain, port) => {
+                if domain.is_empty() || domain.len() > 255 {
+                    return Err(error(format!("invalid domain: {}", domain)));
+                }
+                buf.push(ATYP_DOMAIN);
+                buf.push(domain.len() as u8);
+                buf.extend_from_slice(domain.as_bytes());
+                buf.extend_from_slice(&port.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes an address from the start of `buf`, returning it with the
+    /// bytes taken.
+    fn decode(buf: &[u8]) -> io::Result<(Self, usize)> {
+        let short = || error("short address".to_string());
+        let (ip, len) = match *buf.first().ok_or_else(short)? {
+            ATYP_IPV4 if buf.len() >= 7 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&buf[1..5]);
+                (IpAddr::V4(Ipv4Addr::from(octets)), 5)
+            }
+            ATYP_IPV6 if buf.len() >= 19 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&buf[1..17]);
+                (IpAddr::V6(Ipv6Addr::from(octets)), 17)
+            }
+            ATYP_DOMAIN if buf.len() >= 2 && buf.len() >= buf[1] as usize + 4 => {
+                let len = buf[1] as usize + 2;
+                let domain = String::from_utf8(buf[2..len].to_vec())
+                    .map_err(|_| error("invalid domain".to_string()))?;
+                let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
+                return Ok((Address::Domain(domain, port), len + 2));
+            }
+            ATYP_IPV4 | ATYP_IPV6 | ATYP_DOMAIN => return Err(short()),
+            v => return Err(error(format!("unknown address type: {}", v))),
+        };
+        let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
+        Ok((Address::Ip(SocketAddr::new(ip, port)), len + 2))
+    }
+
+    /// Reads an address of type `atyp`, the port included.
+    async fn read<R: AsyncRead + Unpin>(reader: &mut R, atyp: u8) -> io::Result<Self> {
+        let ip = match atyp {
+            ATYP_IPV4 => {
+                let mut buf = [0u8; 4];
+                reader.read_exact(&mut buf).await?;
+                IpAddr::V4(Ipv4Addr::from(buf))
+            }
+            ATYP_IPV6 => {
+                let mut buf = [0u8; 16];
+                reader.read_exact(&mut buf).await?;
+                IpAddr::V6(Ipv6Addr::from(buf))
+            }
+            ATYP_DOMAIN => {
+                let len = reader.read_u8().await? as usize;
+                let mut buf = vec![0u8; len];
+                reader.read_exact(&mut buf).await?;
+                let domain =
+                    String::from_utf8(buf).map_err(|_| error("invalid domain".to_string()))?;
+                let port = reader.read_u16().await?;
+                return Ok(Address::Domain(domain, port));
+            }
+            v => return Err(error(format!("unknown address type: {}", v))),
+        };
+        let port = reader.read_u16().await?;
+        Ok(Address::Ip(SocketAddr::new(ip, port)))
+    }
+}
+
+/// Parses `host:port`, the host an ip or a domain.
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(v) = s.parse() {
+            return Ok(Address::Ip(v));
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && !host.contains(':') => port
+                .parse()
+                .map(|port| Address::Domain(host.to_string(), port))
+                .map_err(|_| format!("invalid port: {}", s)),
+            _ => Err(format!("invalid address: {}", s)),
+        }
+    }
+}
+
+/// Wraps `data` to be sent to `target` through the udp relay of a proxy.
+pub fn encode_udp(target: &Address, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0, 0, 0];
+    target.encode(&mut buf)?;
+    buf.extend_from_slice(data);
+    Ok(buf)
+}
+
+/// Unwraps a datagram from the udp relay of a proxy, returning the source
+/// and the data. Fragments are not supported.
+pub fn decode_udp(buf: &[u8]) -> io::Result<(Address, &[u8])> {
+    if buf.len() < 4 {
+        return Err(error("short datagram".to_string()));
+    }
+    if buf[2] != 0 {
+        return Err(error("fragmented datagram".to_string()));
+    }
+    let (address, len) = Address::decode(&buf[3..])?;
+    Ok((address, &buf[3 + len..]))
+}
+
+/// Connects to `target` through the socks5 `proxy`, returning the stream
+/// once the proxy has connected.
+pub async fn connect(proxy: &Endpoint, target: &Address) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy.addr()).await?;
+    handshake(&mut stream, proxy).await?;
+    request(&mut stream, CMD_CONNECT, target).await?;
+    Ok(stream)
+}
+
+/// Asks the socks5 `proxy` to relay udp, returning the control connection,
+/// to be kept open as long as the association, and the address of the
+/// relay.
+pub async fn associate(proxy: &Endpoint) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut stream = TcpStream::connect(proxy.addr()).await?;
+    handshake(&mut stream, proxy).await?;
+    let any = Address::Ip(SocketAddr::from(([0, 0, 0, 0], 0)));
+    let relay = match request(&mut stream, CMD_UDP_ASSOCIATE, &any).await? {
+        Address::Ip(v) if v.ip().is_unspecified() => {
+            SocketAddr::new(stream.peer_addr()?.ip(), v.port())
+        }
+        Address::Ip(v) => v,
+        Address::Domain(v, _) => return Err(error(format!("unsupported relay: {}", v))),
+    };
+    Ok((stream, relay))
+}
+
+/// Negotiates the method, authenticating with the credentials of `proxy`
+/// if it has any, RFC 1929.
+async fn handshake(stream: &mut TcpStream, proxy: &Endpoint) -> io::Result<()> {
+    match proxy.credentials {
+        Some(_) => {
+            let methods = [VERSION, 2, METHOD_NONE, METHOD_PASSWORD];
+            stream.write_all(&methods).await?
+        }
+        None => stream.write_all(&[VERSION, 1, METHOD_NONE]).await?,
+    }
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    match (buf, &proxy.credentials) {
+        ([VERSION, METHOD_NONE], _) => Ok(()),
+        ([VERSION, METHOD_PASSWORD], Some((user, pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(error("credentials too long".to_string()));
+            }
+            let mut buf = vec![1, user.len() as u8];
+            buf.extend_from_slice(user.as_bytes());
+            buf.push(pass.len() as u8);
+            buf.extend_from_slice(pass.as_bytes());
+            stream.write_all(&buf).await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(error("authentication failed".to_string()));
+            }
+            Ok(())
+        }
+        _ => Err(error(format!("unsupported method: {:?}", buf))),
+    }
+}
+
+/// Sends a request, returning the address bound by the proxy.
+async fn request(stream: &mut TcpStream, cmd: u8, target: &Address) -> io::Result<Address> {
+    let mut buf = vec![VERSION, cmd, 0];
+    target.encode(&mut buf)?;
+    stream.write_all(&buf).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        return Err(error(format!("unknown version: {}", head[0])));
+    }
+    if head[1] != 0 {
+        return Err(error(format!("request failed, reply: {}", head[1])));
+    }
+    Address::read(stream, head[3]).await
+}
+
+fn error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("socks5 {}", message))
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream, UdpSocket},
+        runtime::Runtime,
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// Serves socks5 CONNECT and UDP ASSOCIATE on a local port, echoing the
+    /// data sent instead of connecting. The targets requested are sent to
+    /// the returned receiver, a request for port 1 is refused. Clients able
+    /// to are asked for the password, `user:pass`.
+    pub async fn stub_server() -> (SocketAddr, mpsc::UnboundedReceiver<Address>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(stub_serve(stream, tx.clone()));
+            }
+        });
+        (addr, rx)
+    }
+
+    async fn stub_serve(
+        mut stream: TcpStream,
+        tx: mpsc::UnboundedSender<Address>,
+    ) -> io::Result<()> {
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await?;
+        let mut methods = vec![0u8; buf[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        if !methods.contains(&METHOD_PASSWORD) {
+            stream.write_all(&[VERSION, METHOD_NONE]).await?;
+        } else {
+            stream.write_all(&[VERSION, METHOD_PASSWORD]).await?;
+            // version, then length prefixed username and password
+            stream.read_u8().await?;
+            let mut credentials = vec![];
+            for _ in 0..2 {
+                let mut v = vec![0u8; stream.read_u8().await? as usize];
+                stream.read_exact(&mut v).await?;
+                credentials.push(v);
+            }
+            if credentials != [b"user".to_vec(), b"pass".to_vec()] {
+                stream.write_all(&[1, 1]).await?;
+                return Ok(());
+            }
+            stream.write_all(&[1, 0]).await?;
+        }
+
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await?;
+        let target = Address::read(&mut stream, head[3]).await?;
+        if head[1] == CMD_UDP_ASSOCIATE {
+            return stub_associate(stream, tx).await;
+        }
+        let refused = matches!(target, Address::Ip(v) if v.port() == 1);
+        let _ = tx.send(target);
+        let rep = if refused { 5 } else { 0 };
+        stream
+            .write_all(&[VERSION, rep, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 80])
+            .await?;
+        if refused {
+            return Ok(());
+        }
+
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        Ok(())
+    }
+
+    // echoes the datagrams from the client as answered by their target
+    async fn stub_associate(
+        mut stream: TcpStream,
+        tx: mpsc::UnboundedSender<Address>,
+    ) -> io::Result<()> {
+        let mut socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let port = socket.local_addr()?.port().to_be_bytes();
+        stream
+            .write_all(&[VERSION, 0, 0, ATYP_IPV4, 0, 0, 0, 0, port[0], port[1]])
+            .await?;
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            while let Ok((n, src)) = socket.recv_from(&mut buf).await {
+                if let Ok((target, _)) = decode_udp(&buf[..n]) {
+                    let _ = tx.send(target);
+                    let _ = socket.send_to(&buf[..n], &src).await;
+                }
+            }
+        });
+        // the association lasts until the client closes
+        let mut buf = [0u8; 1];
+        while stream.read(&mut buf).await? > 0 {}
+        Ok(())
+    }
+
+    pub fn endpoint(addr: SocketAddr) -> Endpoint {
+        format!("socks5://{}", addr).parse().unwrap()
+    }
+
+    #[test]
+    fn test_connect() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (proxy, mut targets) = stub_server().await;
+            let proxy = endpoint(proxy);
+
+            let target = Address::Domain("www.google.com".to_string(), 443);
+            let mut stream = connect(&proxy, &target).await.unwrap();
+            assert_eq!(targets.recv().await.unwrap(), target);
+            stream.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+
+            let target = Address::Ip("[2001:db8::1]:80".parse().unwrap());
+            connect(&proxy, &target).await.unwrap();
+            assert_eq!(targets.recv().await.unwrap(), target);
+
+            let target = Address::Ip("192.0.2.1:1".parse().unwrap());
+            let err = connect(&proxy, &target).await.unwrap_err();
+            assert_eq!(err.to_string(), "socks5 request failed, reply: 5");
+            assert_eq!(targets.recv().await.unwrap(), target);
+
+            let mut proxy = proxy;
+            proxy.credentials = Some(("user".to_string(), "pass".to_string()));
+            let target = Address::Domain("www.google.com".to_string(), 443);
+            connect(&proxy, &target).await.unwrap();
+            assert_eq!(targets.recv().await.unwrap(), target);
+            proxy.credentials = Some(("user".to_string(), "secret".to_string()));
+            let err = connect(&proxy, &target).await.unwrap_err();
+            assert_eq!(err.to_string(), "socks5 authentication failed");
+        });
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            "www.gstatic.com:80".parse::<Address>().unwrap(),
+            Address::Domain("www.gstatic.com".to_string(), 80)
+        );
+        assert_eq!(
+            "[::1]:443".parse::<Address>().unwrap(),
+            Address::Ip("[::1]:443".parse().unwrap())
+        );
+        for v in ["www.gstatic.com", ":80", "www.gstatic.com:http", "::1:80"].iter() {
+            assert!(v.parse::<Address>().is_err(), "{}", v);
+        }
+    }
+
+    #[test]
+    fn test_datagram() {
+        let targets = vec![
+            Address::Ip("93.184.216.34:53".parse().unwrap()),
+            Address::Ip("[2001:db8::1]:443".parse().unwrap()),
+            Address::Domain("www.google.com".to_string(), 443),
+        ];
+        for target in targets {
+            let buf = encode_udp(&target, b"data").unwrap();
+            let (address, data) = decode_udp(&buf).unwrap();
+            assert_eq!(address, target);
+            assert_eq!(data, b"data");
+            assert!(decode_udp(&buf[..buf.len() - 6]).is_err());
+        }
+
+        let mut buf = encode_udp(&Address::Ip("127.0.0.1:53".parse().unwrap()), b"").unwrap();
+        assert!(decode_udp(&buf).unwrap().1.is_empty());
+        buf[2] = 1;
+        assert!(decode_udp(&buf).is_err());
+        assert!(decode_udp(&[0, 0, 0, 9, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_associate() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (proxy, mut targets) = stub_server().await;
+            let (_control, relay) = associate(&endpoint(proxy)).await.unwrap();
+            assert_eq!(relay.ip(), proxy.ip());
+
+            let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let target = Address::Domain("dns.google".to_string(), 53);
+            let datagram = encode_udp(&target, b"query").unwrap();
+            socket.send_to(&datagram, &relay).await.unwrap();
+            assert_eq!(targets.recv().await.unwrap(), target);
+            let mut buf = [0u8; 2048];
+            let n = socket.recv(&mut buf).await.unwrap();
+            assert_eq!(decode_udp(&buf[..n]).unwrap(), (target, &b"query"[..]));
+        });
+    }
+}
@@ -0,0 +1,928 @@
+use std::{
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::{self, Either};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use tokio::{runtime::Runtime, time};
+use trust_dns_client::{
+    op::{Edns, Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{rdata::opt::EdnsOption, Name, RData, RecordType},
+};
+use trust_dns_proto::{
+    error::ProtoError,
+    xfer::{DnsHandle, DnsRequest, DnsRequestOptions},
+};
+use trust_dns_resolver::{
+    config::{NameServerConfig, Protocol, ResolverOpts, TlsClientConfig},
+    name_server::{NameServer, NameServerPool, TokioConnection, TokioConnectionProvider},
+};
+
+use crate::setting::{DnsGroup, Strategy};
+
+type Pool = NameServerPool<TokioConnection, TokioConnectionProvider>;
+
+/// A response and the host it came from.
+pub type Answer = (Message, String);
+
+// the only path trust-dns queries over https
+const DOH_PATH: &str = "/dns-query";
+
+// same as trust-dns, 1500 mtu minus ipv6 and udp headers
+const MAX_PAYLOAD: u16 = 1500 - 40 - 8;
+
+// edns option code of client subnet, RFC 7871
+const ECS_CODE: u16 = 8;
+
+/// A group of upstream name servers, queried with raw messages so the
+/// response is passed through as is, response code and all sections.
+///
+/// Each host is either `ip[:port]` (udp, tcp on truncation) or a url:
+/// `udp://host[:port]`, `tcp://host[:port]`, `tls://host[:port][#name]`
+/// and `https://host[:port]/dns-query[#name]`. `name` is the tls server
+/// name, defaults to `host`, required when `host` is an ip.
+///
+/// A hostname is resolved once at startup, by the plain dns upstreams
+/// given by ip (see `bootstrap`), then by the system resolver, which may
+/// well be kungfu itself.
+#[derive(Clone)]
+pub struct Upstream {
+    servers: Vec<(String, Pool)>,
+    strategy: Strategy,
+    clean: Option<Arc<Upstream>>,
+    poisoned: Arc<Vec<IpNet>>,
+    ecs: Option<ClientSubnet>,
+    timeout: Duration,
+}
+
+/// EDNS Client Subnet sent along the queries of a group.
+#[derive(Clone, Debug, PartialEq)]
+enum ClientSubnet {
+    Fixed(IpNet),
+    /// The subnet of the querying client, /24 or /56.
+    Client,
+}
+
+impl Upstream {
+    /// Queries `hosts` one by one, each waits up to `timeout`. Hostnames
+    /// are resolved by `bootstrap`.
+    pub fn new(
+        hosts: &[String],
+        timeout: Duration,
+        runtime: &Runtime,
+        bootstrap: &[SocketAddr],
+    ) -> Result<Self, String> {
+        Self::with_tls_config(hosts, timeout, runtime, bootstrap, None)
+    }
+
+    pub fn group(
+        group: &DnsGroup,
+        timeout: Duration,
+        runtime: &Runtime,
+        bootstrap: &[SocketAddr],
+    ) -> Result<Self, String> {
+        let mut upstream = Self::new(&group.values, timeout, runtime, bootstrap)?;
+        upstream.strategy = group.strategy.clone();
+        if group.strategy == Strategy::PreferClean {
+            let clean = Self::new(&group.clean, timeout, runtime, bootstrap)?;
+            upstream.clean = Some(Arc::new(clean));
+        }
+        let poisoned = group.poisoned.iter().filter_map(|v| v.parse().ok());
+        upstream.poisoned = Arc::new(poisoned.collect());
+        upstream.ecs = match group.ecs.as_str() {
+            "" => None,
+            "client" => Some(ClientSubnet::Client),
+            v => {
+                let net: IpNet = v.parse().map_err(|_| format!("invalid ecs: {}", v))?;
+                Some(ClientSubnet::Fixed(net.trunc()))
+            }
+        };
+        Ok(upstream)
+    }
+
+    fn with_tls_config(
+        hosts: &[String],
+        timeout: Duration,
+        runtime: &Runtime,
+        bootstrap: &[SocketAddr],
+        tls_config: Option<TlsClientConfig>,
+    ) -> Result<Self, String> {
+        let handle = runtime.handle().to_owned();
+        let options = ResolverOpts {
+            timeout,
+            ..ResolverOpts::default()
+        };
+
+        let mut servers = vec![];
+        for host in hosts {
+            let mut datagram_conns = vec![];
+            let mut stream_conns = vec![];
+            let parsed = parse_host(host)?;
+            let addrs = parsed
+                .resolve(bootstrap, timeout)
+                .map_err(|e| format!("{}, host: {}", e, host))?;
+            for (addr, protocol) in addrs
+                .iter()
+                .flat_map(|a| parsed.protocols.iter().map(move |p| (*a, *p)))
+            {
+                let config = NameServerConfig {
+                    socket_addr: addr,
+                    protocol,
+                    tls_dns_name: parsed.tls_dns_name.clone(),
+                    tls_config: tls_config.clone(),
+                };
+                let conn = NameServer::new(config, options, handle.clone());
+                match protocol {
+                    Protocol::Udp => datagram_conns.push(conn),
+                    _ => stream_conns.push(conn),
+                }
+            }
+
+            let pool = NameServerPool::from_nameservers(
+                &options,
+                datagram_conns,
+                stream_conns,
+                TokioConnectionProvider::new(handle.clone()),
+            );
+            servers.push((host.clone(), pool));
+        }
+
+        if servers.is_empty() {
+            return Err("dns upstream is empty".to_string());
+        }
+
+        Ok(Upstream {
+            servers,
+            strategy: Strategy::Sequential,
+            clean: None,
+            poisoned: Arc::new(vec![]),
+            ecs: None,
+            timeout,
+        })
+    }
+
+    /// The client subnet to send along the queries of `client`. Its own
+    /// subnet is only sent for a public address.
+    pub fn subnet(&self, client: IpAddr) -> Option<IpNet> {
+        match self.ecs.as_ref()? {
+            ClientSubnet::Fixed(net) => Some(*net),
+            ClientSubnet::Client => client_subnet(client),
+        }
+    }
+
+    /// Resolves `query`, with `subnet` as EDNS Client Subnet if any.
+    pub async fn lookup(&self, query: Query, subnet: Option<IpNet>) -> Result<Answer, ProtoError> {
+        let request = request_message(query, subnet);
+        match &self.clean {
+            Some(clean) => self.prefer_clean(clean, &request).await,
+            None if self.strategy == Strategy::Parallel => self.race(&request).await,
+            None => self.sequential(&request).await,
+        }
+    }
+
+    /// Tries the servers in order until one gives a valid answer, the last
+    /// answer is returned if none does.
+    async fn sequential(&self, request: &Message) -> Result<Answer, ProtoError> {
+        let mut res = Err(ProtoError::from("dns upstream is empty"));
+        for (host, server) in &self.servers {
+            res = self.lookup_server(host, server, request).await;
+            if is_valid(&res) {
+                break;
+            }
+        }
+        res
+    }
+
+    /// Queries all servers at once, the first valid answer wins.
+    async fn race(&self, request: &Message) -> Result<Answer, ProtoError> {
+        let lookups = self.servers.iter().map(|(host, server)| {
+            let res = self.lookup_server(host, server, request);
+            Box::pin(async move {
+                match res.await {
+                    Ok((res, _)) if res.response_code() == ResponseCode::ServFail => {
+                        Err(ProtoError::from("servfail"))
+                    }
+                    res => res,
+                }
+            })
+        });
+        let (res, _) = future::select_ok(lookups).await?;
+        Ok(res)
+    }
+
+    /// Races the servers against the clean ones, preferring the answer of
+    /// the servers unless it is polluted, i.e. has an ip in `poisoned`.
+    ///
+    /// `prefer-clean` takes the place of the sequential and parallel
+    /// strategies: the servers of each side are always raced in parallel.
+    async fn prefer_clean(
+        &self,
+        clean: &Upstream,
+        request: &Message,
+    ) -> Result<Answer, ProtoError> {
+        let name = request.queries()[0].name().to_string();
+        let res = Box::pin(self.race(request));
+        let clean_res = Box::pin(clean.race(request));
+
+        match future::select(res, clean_res).await {
+            Either::Left((res, clean_res)) => match res {
+                Ok(res) if !self.is_poisoned(&res.0) => Ok(res),
+                Ok(_) => {
+                    debug!("drop poisoned answer of {}", name);
+                    clean_res.await
+                }
+                Err(_) => clean_res.await,
+            },
+            Either::Right((clean_res, res)) => match res.await {
+                Ok(res) if !self.is_poisoned(&res.0) => Ok(res),
+                res if clean_res.is_err() => res,
+                _ => clean_res,
+            },
+        }
+    }
+
+    async fn lookup_server(
+        &self,
+        host: &str,
+        server: &Pool,
+        request: &Message,
+    ) -> Result<Answer, ProtoError> {
+        let mut server = server.clone();
+        let request = DnsRequest::new(request.clone(), DnsRequestOptions::default());
+        let res = server.send(request);
+        match time::timeout(self.timeout, res).await {
+            Ok(res) => Ok((res?.into(), host.to_string())),
+            Err(_) => Err(ProtoError::from("request timed out")),
+        }
+    }
+
+    fn is_poisoned(&self, res: &Message) -> bool {
+        res.answers().iter().any(|v| {
+            let ip = match v.rdata() {
+                RData::A(ip) => IpAddr::V4(*ip),
+                RData::AAAA(ip) => IpAddr::V6(*ip),
+                _ => return false,
+            };
+            self.poisoned.iter().any(|net| net.contains(&ip))
+        })
+    }
+}
+
+/// Builds the query like trust-dns does, the id is assigned on sending.
+fn request_message(query: Query, subnet: Option<IpNet>) -> Message {
+    let mut message = Message::new();
+    message
+        .add_query(query)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true);
+    let mut edns = Edns::new();
+    edns.set_max_payload(MAX_PAYLOAD);
+    edns.set_version(0);
+    if let Some(subnet) = subnet {
+        edns.set_option(ecs_option(subnet));
+    }
+    message.set_edns(edns);
+    message
+}
+
+/// Encodes `subnet` as a client subnet option, the address is cut to the
+/// bytes covered by the prefix.
+fn ecs_option(subnet: IpNet) -> EdnsOption {
+    let (family, octets) = match subnet.network() {
+        IpAddr::V4(ip) => (1u16, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (2u16, ip.octets().to_vec()),
+    };
+    let prefix = subnet.prefix_len();
+    let mut data = family.to_be_bytes().to_vec();
+    data.push(prefix);
+    // scope prefix, always 0 in queries
+    data.push(0);
+    data.extend_from_slice(&octets[..(prefix as usize + 7) / 8]);
+    EdnsOption::Unknown(ECS_CODE, data)
+}
+
+/// The /24 or /56 of a public client address, private and local ones
+/// tell the upstream nothing.
+fn client_subnet(client: IpAddr) -> Option<IpNet> {
+    match client {
+        IpAddr::V4(ip) => {
+            if ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() {
+                return None;
+            }
+            Ipv4Net::new(ip, 24).ok().map(|v| IpNet::V4(v.trunc()))
+        }
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            // unique local fc00::/7 and link local fe80::/10
+            if ip.is_loopback()
+                || ip.is_unspecified()
+                || segment & 0xfe00 == 0xfc00
+                || segment & 0xffc0 == 0xfe80
+            {
+                return None;
+            }
+            Ipv6Net::new(ip, 56).ok().map(|v| IpNet::V6(v.trunc()))
+        }
+    }
+}
+
+fn is_valid(res: &Result<Answer, ProtoError>) -> bool {
+    match res {
+        Ok((res, _)) => res.response_code() != ResponseCode::ServFail,
+        Err(_) => false,
+    }
+}
+
+/// A parsed upstream host, see `Upstream`.
+#[derive(Debug, PartialEq)]
+pub struct Host {
+    server: Server,
+    protocols: Vec<Protocol>,
+    tls_dns_name: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Server {
+    Addr(SocketAddr),
+    /// A hostname and port, resolved at startup.
+    Name(String, u16),
+}
+
+/// Parses a host of an upstream, see `Upstream`. Hostnames are left
+/// unresolved.
+pub fn parse_host(host: &str) -> Result<Host, String> {
+    let (scheme, rest) = match host.split_once("://") {
+        Some(v) => v,
+        None => {
+            let addr = parse_addr(host, 53).ok_or_else(|| format!("invalid dns host: {}", host))?;
+            return Ok(Host {
+                server: Server::Addr(addr),
+                protocols: vec![Protocol::Udp, Protocol::Tcp],
+                tls_dns_name: None,
+            });
+        }
+    };
+
+    let (protocol, port) = match scheme.to_lowercase().as_str() {
+        "udp" => (Protocol::Udp, 53),
+        "tcp" => (Protocol::Tcp, 53),
+        "tls" => (Protocol::Tls, 853),
+        "https" => (Protocol::Https, 443),
+        _ => return Err(format!("unsupported dns host scheme: {}", host)),
+    };
+
+    let (rest, name) = match rest.split_once('#') {
+        Some((rest, name)) => (rest, Some(name.to_string())),
+        None => (rest, None),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    if !path.is_empty() && (protocol != Protocol::Https || path != DOH_PATH) {
+        return Err(format!("unsupported dns host path: {}", host));
+    }
+
+    let server = match parse_addr(authority, port) {
+        Some(addr) => Server::Addr(addr),
+        None => parse_name(authority, port).ok_or_else(|| format!("invalid dns host: {}", host))?,
+    };
+
+    let tls_dns_name = match (protocol, &server) {
+        (Protocol::Tls, _) | (Protocol::Https, _) if name.is_some() => name,
+        (Protocol::Tls, Server::Name(v, _)) | (Protocol::Https, Server::Name(v, _)) => {
+            Some(v.clone())
+        }
+        (Protocol::Tls, _) | (Protocol::Https, _) => {
+            return Err(format!("missing tls name, use `#name`: {}", host));
+        }
+        _ => None,
+    };
+
+    Ok(Host {
+        server,
+        protocols: vec![protocol],
+        tls_dns_name,
+    })
+}
+
+/// The plain dns servers of `hosts` given by ip, to resolve the others.
+pub fn bootstrap<'a, I: IntoIterator<Item = &'a String>>(hosts: I) -> Vec<SocketAddr> {
+    hosts
+        .into_iter()
+        .filter_map(|v| parse_host(v).ok())
+        .filter(|v| v.protocols.contains(&Protocol::Udp))
+        .filter_map(|v| match v.server {
+            Server::Addr(addr) => Some(addr),
+            Server::Name(..) => None,
+        })
+        .collect()
+}
+
+impl Host {
+    /// The addresses of the server, a hostname asked to the `bootstrap`
+    /// servers in turn, then to the system resolver.
+    fn resolve(
+        &self,
+        bootstrap: &[SocketAddr],
+        timeout: Duration,
+    ) -> Result<Vec<SocketAddr>, String> {
+        let (name, port) = match &self.server {
+            Server::Addr(addr) => return Ok(vec![*addr]),
+            Server::Name(name, port) => (name, *port),
+        };
+        for server in bootstrap {
+            match bootstrap_lookup(*server, name, timeout) {
+                Ok(ips) if !ips.is_empty() => {
+                    debug!("resolve dns host {} by {}: {:?}", name, server, ips);
+                    return Ok(ips
+                        .into_iter()
+                        .map(|ip| SocketAddr::new(ip, port))
+                        .collect());
+                }
+                Ok(_) => debug!("resolve dns host {} by {}: no address", name, server),
+                Err(e) => debug!("resolve dns host {} by {}, err: {}", name, server, e),
+            }
+        }
+
+        let addrs: Vec<SocketAddr> = (name.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|e| format!("resolve dns host err: {}", e))?
+            .collect();
+        if addrs.is_empty() {
+            return Err("resolve dns host err: no address".to_string());
+        }
+        Ok(addrs)
+    }
+}
+
+/// The A then AAAA records of `name`, asked to `server` over udp.
+fn bootstrap_lookup(
+    server: SocketAddr,
+    name: &str,
+    timeout: Duration,
+) -> Result<Vec<IpAddr>, String> {
+    let bind = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    socket.connect(server).map_err(|e| e.to_string())?;
+    let name = Name::from_str(name).map_err(|e| e.to_string())?;
+
+    let mut ips = vec![];
+    for record_type in [RecordType::A, RecordType::AAAA].iter() {
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |v| v.subsec_nanos() as u16);
+        let mut request = Message::new();
+        request.set_id(id);
+        request.set_recursion_desired(true);
+        request.add_query(Query::query(name.clone(), *record_type));
+        let request = request.to_vec().map_err(|e| e.to_string())?;
+        socket.send(&request).map_err(|e| e.to_string())?;
+
+        let mut buf = [0u8; 4096];
+        let response = loop {
+            let n = socket.recv(&mut buf).map_err(|e| e.to_string())?;
+            match Message::from_vec(&buf[..n]) {
+                Ok(v) if v.id() == id => break v,
+                // a late answer to an earlier query
+                _ => continue,
+            }
+        };
+        for answer in response.answers() {
+            let ip = match answer.rdata() {
+                RData::A(ip) => IpAddr::V4(*ip),
+                RData::AAAA(ip) => IpAddr::V6(*ip),
+                _ => continue,
+            };
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+    }
+    Ok(ips)
+}
+
+/// Parses `ip` or `ip:port`.
+fn parse_addr(host: &str, port: u16) -> Option<SocketAddr> {
+    host.parse::<SocketAddr>()
+        .or_else(|_| host.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, port)))
+        .ok()
+}
+
+/// Parses `hostname` or `hostname:port`.
+fn parse_name(host: &str, port: u16) -> Option<Server> {
+    let (name, port) = match host.split_once(':') {
+        Some((name, v)) => (name, v.parse().ok()?),
+        None => (host, port),
+    };
+    let valid = name
+        .split('.')
+        .all(|v| !v.is_empty() && v.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+    if !valid {
+        return None;
+    }
+    Some(Server::Name(name.to_lowercase(), port))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, pin::Pin, str::FromStr, sync::Arc, time::Duration};
+
+    use futures::{future, Future};
+    use rustls::{Certificate, ClientConfig, PrivateKey};
+    use tokio::net::TcpListener;
+    use trust_dns_client::{
+        op::{Header, MessageType},
+        rr::{Name, RData, Record, RecordType},
+    };
+    use trust_dns_server::{
+        authority::MessageResponseBuilder,
+        server::{Request, RequestHandler, ResponseHandler},
+        ServerFuture,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_parse_host() {
+        let addr = |v: &str| Server::Addr(v.parse::<SocketAddr>().unwrap());
+        let host = |server: Server, protocols: Vec<Protocol>, tls_dns_name: Option<&str>| Host {
+            server,
+            protocols,
+            tls_dns_name: tls_dns_name.map(|v| v.to_string()),
+        };
+        let name = Some("cloudflare-dns.com");
+
+        assert_eq!(
+            parse_host("1.2.4.8").unwrap(),
+            host(addr("1.2.4.8:53"), vec![Protocol::Udp, Protocol::Tcp], None)
+        );
+        assert_eq!(
+            parse_host("udp://[::1]:5353").unwrap(),
+            host(addr("[::1]:5353"), vec![Protocol::Udp], None)
+        );
+        assert_eq!(
+            parse_host("tcp://114.114.114.114").unwrap(),
+            host(addr("114.114.114.114:53"), vec![Protocol::Tcp], None)
+        );
+        assert_eq!(
+            parse_host("tls://1.1.1.1#cloudflare-dns.com").unwrap(),
+            host(addr("1.1.1.1:853"), vec![Protocol::Tls], name)
+        );
+        assert_eq!(
+            parse_host("https://1.1.1.1/dns-query#cloudflare-dns.com").unwrap(),
+            host(addr("1.1.1.1:443"), vec![Protocol::Https], name)
+        );
+
+        // hostnames are the tls name unless given
+        assert_eq!(
+            parse_host("https://dns.google/dns-query").unwrap(),
+            host(
+                Server::Name("dns.google".to_string(), 443),
+                vec![Protocol::Https],
+                Some("dns.google")
+            )
+        );
+        assert_eq!(
+            parse_host("tls://localhost:8853#localhost").unwrap(),
+            host(
+                Server::Name("localhost".to_string(), 8853),
+                vec![Protocol::Tls],
+                Some("localhost")
+            )
+        );
+        assert_eq!(
+            parse_host("tcp://Dns.Example:5353").unwrap(),
+            host(
+                Server::Name("dns.example".to_string(), 5353),
+                vec![Protocol::Tcp],
+                None
+            )
+        );
+
+        assert!(parse_host("1.2.4.300").is_err());
+        assert!(parse_host("dns.google").is_err());
+        assert!(parse_host("quic://1.1.1.1").is_err());
+        assert!(parse_host("tls://1.1.1.1").is_err());
+        assert!(parse_host("https://1.1.1.1/resolve#dns.google").is_err());
+        assert!(parse_host("tcp://1.1.1.1/dns-query").is_err());
+        assert!(parse_host("tls://").is_err());
+        assert!(parse_host("tls://dns..google").is_err());
+        assert!(parse_host("tls://dns.google:x").is_err());
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let hosts: Vec<String> = [
+            "1.2.4.8",
+            "udp://[::1]:5353",
+            "tcp://114.114.114.114",
+            "udp://dns.example",
+            "https://1.1.1.1/dns-query#cloudflare-dns.com",
+        ]
+        .iter()
+        .map(|v| v.to_string())
+        .collect();
+        assert_eq!(
+            bootstrap(&hosts),
+            vec![
+                "1.2.4.8:53".parse::<SocketAddr>().unwrap(),
+                "[::1]:5353".parse().unwrap()
+            ]
+        );
+    }
+
+    #[derive(Clone)]
+    struct TestHandler;
+
+    impl RequestHandler for TestHandler {
+        type ResponseFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+        fn handle_request<R: ResponseHandler>(
+            &self,
+            request: Request,
+            response_handle: R,
+        ) -> Self::ResponseFuture {
+            let request = request.message;
+            let name = request.queries()[0].original().name().clone();
+            let answers = [Record::from_rdata(
+                name,
+                300,
+                RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+            )];
+
+            let mut header = Header::new();
+            header.set_id(request.id());
+            header.set_message_type(MessageType::Response);
+            let builder = MessageResponseBuilder::new(Some(request.raw_queries()));
+            let response = builder.build(
+                header,
+                records(&answers),
+                records(&[]),
+                records(&[]),
+                records(&[]),
+            );
+            let _ = response_handle.send_response(response);
+            Box::pin(future::ready(()))
+        }
+    }
+
+    fn records(records: &[Record]) -> Box<dyn Iterator<Item = &Record> + Send + '_> {
+        Box::new(records.iter())
+    }
+
+    /// Starts a tls and a https dns server with a self signed certificate
+    /// of `localhost`, returns their addresses and the client config
+    /// trusting it.
+    fn test_server(rt: &Runtime) -> (SocketAddr, SocketAddr, TlsClientConfig) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let key = PrivateKey(cert.serialize_private_key_der());
+
+        let mut server = ServerFuture::new(TestHandler);
+        let bind = || {
+            rt.handle()
+                .block_on(TcpListener::bind("127.0.0.1:0"))
+                .unwrap()
+        };
+        let timeout = Duration::from_secs(5);
+
+        let listener = bind();
+        let tls_addr = listener.local_addr().unwrap();
+        let certs = (vec![Certificate(der.clone())], key.clone());
+        server
+            .register_tls_listener(listener, timeout, certs, rt)
+            .unwrap();
+
+        let listener = bind();
+        let https_addr = listener.local_addr().unwrap();
+        let certs = (vec![Certificate(der.clone())], key);
+        server
+            .register_https_listener(listener, timeout, certs, "localhost".to_string(), rt)
+            .unwrap();
+        rt.spawn(async move {
+            let _ = server.block_until_done().await;
+        });
+
+        let mut config = ClientConfig::new();
+        config.root_store.add(&Certificate(der)).unwrap();
+        config.alpn_protocols.push(b"h2".to_vec());
+        (tls_addr, https_addr, TlsClientConfig(Arc::new(config)))
+    }
+
+    #[test]
+    fn test_lookup() {
+        let rt = Runtime::new().unwrap();
+        let (tls_addr, https_addr, config) = test_server(&rt);
+        let timeout = Duration::from_secs(2);
+        let query = Query::query(Name::from_str("www.google.com.").unwrap(), RecordType::A);
+        let lookup = |hosts: Vec<String>| {
+            let upstream =
+                Upstream::with_tls_config(&hosts, timeout, &rt, &[], Some(config.clone())).unwrap();
+            rt.handle().block_on(upstream.lookup(query.clone(), None))
+        };
+
+        for hosts in [
+            vec![format!("tls://{}#localhost", tls_addr)],
+            vec![format!("https://{}/dns-query#localhost", https_addr)],
+            // resolved by the system resolver
+            vec![format!("https://localhost:{}/dns-query", https_addr.port())],
+            // udp to a closed port fails over to the tls server
+            vec![
+                "udp://127.0.0.1:1".to_string(),
+                format!("tls://{}#localhost", tls_addr),
+            ],
+        ] {
+            let (res, host) =
+                lookup(hosts.clone()).unwrap_or_else(|e| panic!("{:?}: {}", hosts, e));
+            assert_eq!(Some(&host), hosts.last());
+            assert_eq!(
+                res.answers()[0].rdata(),
+                &RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+                "{:?}",
+                hosts
+            );
+        }
+
+        // the certificate is not trusted by default
+        let hosts = vec![format!("tls://{}#localhost", tls_addr)];
+        let upstream = Upstream::new(&hosts, timeout, &rt, &[]).unwrap();
+        assert!(rt.handle().block_on(upstream.lookup(query, None)).is_err());
+    }
+
+    #[test]
+    fn test_lookup_bootstrap() {
+        let rt = Runtime::new().unwrap();
+        let (_, https_addr, config) = test_server(&rt);
+        let timeout = Duration::from_secs(2);
+        let query = Query::query(Name::from_str("www.google.com.").unwrap(), RecordType::A);
+        let bootstrap = |ip: Option<Ipv4Addr>| {
+            let host = udp_server(ip, Duration::from_millis(0));
+            host.trim_start_matches("udp://")
+                .parse::<SocketAddr>()
+                .unwrap()
+        };
+
+        // doh.test is only known to the bootstrap server, which answers
+        // 127.0.0.1 after a failing one
+        let hosts = vec![format!(
+            "https://doh.test:{}/dns-query#localhost",
+            https_addr.port()
+        )];
+        let servers = [bootstrap(None), bootstrap(Some(Ipv4Addr::LOCALHOST))];
+        let upstream =
+            Upstream::with_tls_config(&hosts, timeout, &rt, &servers, Some(config)).unwrap();
+        let (res, host) = rt.handle().block_on(upstream.lookup(query, None)).unwrap();
+        assert_eq!(host, hosts[0]);
+        assert_eq!(
+            res.answers()[0].rdata(),
+            &RData::A(Ipv4Addr::new(1, 2, 3, 4))
+        );
+
+        let hosts = vec!["https://doh.invalid/dns-query".to_string()];
+        let err = Upstream::new(&hosts, timeout, &rt, &[bootstrap(None)])
+            .err()
+            .unwrap();
+        assert!(err.contains("doh.invalid"), "{}", err);
+    }
+
+    /// Starts a udp dns server answering A queries with `ip` after `delay`,
+    /// or SERVFAIL if `ip` is `None`.
+    fn udp_server(ip: Option<Ipv4Addr>, delay: Duration) -> String {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((n, src)) = socket.recv_from(&mut buf) {
+                let request = match Message::from_vec(&buf[..n]) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                let socket = socket.try_clone().unwrap();
+                std::thread::spawn(move || {
+                    std::thread::sleep(delay);
+                    let mut response = Message::new();
+                    response.set_id(request.id());
+                    response.set_message_type(MessageType::Response);
+                    response.add_queries(request.queries().to_vec());
+                    match ip {
+                        Some(ip) => {
+                            let name = request.queries()[0].name().clone();
+                            response.add_answer(Record::from_rdata(name, 300, RData::A(ip)));
+                        }
+                        None => {
+                            response.set_response_code(ResponseCode::ServFail);
+                        }
+                    }
+                    let _ = socket.send_to(&response.to_vec().unwrap(), src);
+                });
+            }
+        });
+        format!("udp://{}", addr)
+    }
+
+    #[test]
+    fn test_strategy() {
+        let rt = Runtime::new().unwrap();
+        let timeout = Duration::from_millis(500);
+        let dead = udp_server(Some(Ipv4Addr::new(9, 9, 9, 9)), Duration::from_secs(5));
+        let servfail = udp_server(None, Duration::from_millis(0));
+        let slow = udp_server(Some(Ipv4Addr::new(1, 1, 1, 1)), Duration::from_millis(200));
+        let fast = udp_server(Some(Ipv4Addr::new(2, 2, 2, 2)), Duration::from_millis(0));
+
+        let query = Query::query(Name::from_str("www.google.com.").unwrap(), RecordType::A);
+        let lookup = |strategy: Strategy, values: &[&String], clean: &[&String]| {
+            let group = DnsGroup {
+                name: "test".to_string(),
+                values: values.iter().map(|v| v.to_string()).collect(),
+                strategy,
+                clean: clean.iter().map(|v| v.to_string()).collect(),
+                poisoned: vec!["2.2.2.0/24".to_string()],
+                ecs: String::new(),
+            };
+            let upstream = Upstream::group(&group, timeout, &rt, &[]).unwrap();
+            let (res, _) = rt
+                .handle()
+                .block_on(upstream.lookup(query.clone(), None))
+                .map_err(|e| e.to_string())?;
+            Ok(match res.answers().first().map(|v| v.rdata()) {
+                Some(RData::A(ip)) => Some(*ip),
+                _ => None,
+            })
+        };
+        let ip =
+            |v: u8| -> Result<Option<Ipv4Addr>, String> { Ok(Some(Ipv4Addr::new(v, v, v, v))) };
+
+        let servers = [&dead, &servfail, &slow, &fast];
+        assert_eq!(lookup(Strategy::Sequential, &servers, &[]), ip(1));
+        assert!(lookup(Strategy::Sequential, &[&dead, &servfail], &[]).is_err());
+        assert_eq!(lookup(Strategy::Parallel, &servers, &[]), ip(2));
+        assert!(lookup(Strategy::Parallel, &[&dead, &servfail], &[]).is_err());
+
+        // polluted answer replaced by the clean one
+        assert_eq!(lookup(Strategy::PreferClean, &[&fast], &[&slow]), ip(1));
+        // the answer of the servers is preferred, even if slower
+        let clean = [&fast, &dead];
+        assert_eq!(lookup(Strategy::PreferClean, &[&slow], &clean), ip(1));
+        assert_eq!(lookup(Strategy::PreferClean, &[&dead], &clean), ip(2));
+    }
+
+    #[test]
+    fn test_ecs() {
+        use trust_dns_client::rr::rdata::opt::EdnsCode;
+
+        let ecs = |subnet: &str| {
+            let query = Query::query(Name::from_str("www.google.com.").unwrap(), RecordType::A);
+            let request = super::request_message(query, subnet.parse().ok());
+            let request = Message::from_vec(&request.to_vec().unwrap()).unwrap();
+            match request.edns().unwrap().option(EdnsCode::Subnet) {
+                Some(EdnsOption::Unknown(8, data)) => Some(data.clone()),
+                _ => None,
+            }
+        };
+        assert_eq!(ecs("203.0.113.0/24"), Some(vec![0, 1, 24, 0, 203, 0, 113]));
+        assert_eq!(ecs("203.0.112.0/20"), Some(vec![0, 1, 20, 0, 203, 0, 112]));
+        assert_eq!(
+            ecs("2001:db8:1200::/40"),
+            Some(vec![0, 2, 40, 0, 0x20, 0x01, 0x0d, 0xb8, 0x12])
+        );
+        assert_eq!(ecs(""), None);
+
+        let rt = Runtime::new().unwrap();
+        let group = |ecs: &str| DnsGroup {
+            name: "test".to_string(),
+            values: vec!["127.0.0.1".to_string()],
+            strategy: Strategy::Sequential,
+            clean: vec![],
+            poisoned: vec![],
+            ecs: ecs.to_string(),
+        };
+        let timeout = Duration::from_secs(1);
+        let client: IpAddr = "198.51.100.7".parse().unwrap();
+
+        let upstream = Upstream::group(&group(""), timeout, &rt, &[]).unwrap();
+        assert_eq!(upstream.subnet(client), None);
+        let upstream = Upstream::group(&group("203.0.113.9/24"), timeout, &rt, &[]).unwrap();
+        assert_eq!(upstream.subnet(client), "203.0.113.0/24".parse().ok());
+        let upstream = Upstream::group(&group("client"), timeout, &rt, &[]).unwrap();
+        assert_eq!(upstream.subnet(client), "198.51.100.0/24".parse().ok());
+        assert_eq!(
+            upstream.subnet("2001:db8:1:2ff::1".parse().unwrap()),
+            "2001:db8:1:200::/56".parse().ok()
+        );
+        assert_eq!(upstream.subnet("192.168.1.10".parse().unwrap()), None);
+        assert_eq!(upstream.subnet("127.0.0.1".parse().unwrap()), None);
+        assert_eq!(upstream.subnet("fd00::1".parse().unwrap()), None);
+        assert!(Upstream::group(&group("office"), timeout, &rt, &[]).is_err());
+    }
+}